@@ -0,0 +1,251 @@
+//! An HTTP sidecar around `TokenBucket`, for services that can't embed the crate
+//!
+//! Configured from the environment:
+//! - `RATELIMIT_TABLE`: the DynamoDB table, required
+//! - `RATELIMIT_ADDR`: the address to listen on, `0.0.0.0:8080` by default
+//! - `RATELIMIT_MAX_TOKENS`, `RATELIMIT_STARTING_TOKENS`, `RATELIMIT_REFILL_RATE` and
+//!   `RATELIMIT_REFILL_INTERVAL`: the default settings, 100, 100, 10 and 60 by default
+//!
+//! `POST /limit` takes `{"id": "...", "cost": 1}` and responds with
+//! `{"allow": true, "remaining": 9, "retry_after": null}`. A deny is a 429 with a `Retry-After`
+//! header, unless the cost can never be afforded, in which case `retry_after` is `u64::MAX`.
+//! `PUT /settings/{id}` takes a `RateLimitSettings` and stores it for the id.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{post, put},
+    Json, Router,
+};
+use distributed_ratelimit::{
+    headers, LimitResult, RateLimitSettings, TokenBucket, TokenBucketClient, TokenBucketError,
+    TokenDynamoClient,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, error::Error, sync::Arc};
+
+struct AppState<C: TokenBucketClient> {
+    bucket: TokenBucket<C>,
+    client: C,
+}
+
+#[derive(Deserialize)]
+struct LimitRequest {
+    id: String,
+    #[serde(default = "default_cost")]
+    cost: u64,
+}
+
+fn default_cost() -> u64 {
+    1
+}
+
+#[derive(Serialize)]
+struct LimitResponse {
+    allow: bool,
+    remaining: Option<u64>,
+    retry_after: Option<u64>,
+}
+
+struct ApiError(TokenBucketError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            TokenBucketError::InvalidKey(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.0.to_string()).into_response()
+    }
+}
+
+async fn limit<C: TokenBucketClient<Error = TokenBucketError>>(
+    State(state): State<Arc<AppState<C>>>,
+    Json(request): Json<LimitRequest>,
+) -> Result<Response, ApiError> {
+    let result = state
+        .bucket
+        .limit(&request.id, request.cost)
+        .await
+        .map_err(ApiError)?;
+    Ok(match result {
+        LimitResult::Allow { remaining } => Json(LimitResponse {
+            allow: true,
+            remaining: Some(remaining),
+            retry_after: None,
+        })
+        .into_response(),
+        LimitResult::Deny {
+            retry_after,
+            recoverable,
+            ..
+        } => {
+            let body = Json(LimitResponse {
+                allow: false,
+                remaining: None,
+                retry_after: Some(retry_after),
+            });
+            if !recoverable {
+                (StatusCode::TOO_MANY_REQUESTS, body).into_response()
+            } else {
+                let retry_after = headers::retry_after_delta_seconds(retry_after);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after)],
+                    body,
+                )
+                    .into_response()
+            }
+        }
+    })
+}
+
+async fn put_settings<C: TokenBucketClient<Error = TokenBucketError>>(
+    State(state): State<Arc<AppState<C>>>,
+    Path(id): Path<String>,
+    Json(settings): Json<RateLimitSettings>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .client
+        .put_settings(&id, settings)
+        .await
+        .map_err(ApiError)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn router<C>(state: Arc<AppState<C>>) -> Router
+where
+    C: TokenBucketClient<Error = TokenBucketError> + Send + 'static,
+{
+    Router::new()
+        .route("/limit", post(limit::<C>))
+        .route("/settings/{id}", put(put_settings::<C>))
+        .with_state(state)
+}
+
+fn env_u64(name: &str, default: u64) -> Result<u64, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => Ok(value.parse().map_err(|e| format!("{name}: {e}"))?),
+        Err(_) => Ok(default),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let table_name = env::var("RATELIMIT_TABLE").map_err(|_| "RATELIMIT_TABLE must be set")?;
+    let addr = env::var("RATELIMIT_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
+    let default_settings = RateLimitSettings::new(
+        env_u64("RATELIMIT_MAX_TOKENS", 100)?,
+        env_u64("RATELIMIT_STARTING_TOKENS", 100)?,
+        env_u64("RATELIMIT_REFILL_RATE", 10)?,
+        env_u64("RATELIMIT_REFILL_INTERVAL", 60)?,
+    )?;
+
+    let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+    let client = TokenDynamoClient::new(aws_sdk_dynamodb::Client::new(&config), table_name);
+    let bucket = TokenBucket::new(client.clone(), default_settings)?;
+    let app = router(Arc::new(AppState { bucket, client }));
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use distributed_ratelimit::InMemoryClient;
+    use tower::ServiceExt;
+
+    fn app() -> (Router, InMemoryClient) {
+        let client = InMemoryClient::new();
+        let settings = RateLimitSettings::new(2, 2, 1, 60).unwrap();
+        let bucket = TokenBucket::new(client.clone(), settings).unwrap();
+        let state = AppState {
+            bucket,
+            client: client.clone(),
+        };
+        (router(Arc::new(state)), client)
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, body: serde_json::Value) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn limit_allows_then_denies() {
+        let (app, _) = app();
+
+        let allowed = send(
+            &app,
+            "POST",
+            "/limit",
+            serde_json::json!({"id": "a", "cost": 2}),
+        )
+        .await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(
+            json(allowed).await,
+            serde_json::json!({"allow": true, "remaining": 0, "retry_after": null})
+        );
+
+        let denied = send(&app, "POST", "/limit", serde_json::json!({"id": "a"})).await;
+        assert_eq!(denied.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(denied.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(json(denied).await["allow"], false);
+
+        let never = send(
+            &app,
+            "POST",
+            "/limit",
+            serde_json::json!({"id": "b", "cost": 3}),
+        )
+        .await;
+        assert_eq!(never.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(!never.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(json(never).await["retry_after"], u64::MAX);
+
+        let invalid = send(&app, "POST", "/limit", serde_json::json!({"id": ""})).await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn put_settings_stores_them() {
+        let (app, client) = app();
+        let settings = RateLimitSettings::new(5, 5, 1, 60).unwrap();
+
+        let response = send(
+            &app,
+            "PUT",
+            "/settings/a",
+            serde_json::to_value(settings).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(client.get_settings("a").await.unwrap(), Some(settings));
+
+        let allowed = send(
+            &app,
+            "POST",
+            "/limit",
+            serde_json::json!({"id": "a", "cost": 5}),
+        )
+        .await;
+        assert_eq!(json(allowed).await["remaining"], 0);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{lock, PutOutcome, RateLimitItem, RateLimitSettings, TokenBucketClient};
+use aws_sdk_dynamodb::config::AsyncSleep;
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+/// The default for `BufferedClient::flush_interval`
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+/// Buffers spends in memory and flushes each key's net change to `inner` on a timer
+///
+/// A `put_limit` that only changes the tokens of the bucket last read for the key, i.e. a spend
+/// without a refill, is recorded as a delta instead of being written. `run` flushes the deltas
+/// every `flush_interval` with `refund` and `spend`, which add atomically on DynamoDB, so a hot key
+/// costs one write per flush instead of one per call. Writes that move `last_updated` go straight
+/// to `inner`, so a key still costs one write per `refill_interval` it's used in, or one per call
+/// under `TokenAccounting::FixedPoint`, which moves `last_updated` on every refill.
+///
+/// The tradeoff:
+/// - Other nodes don't see buffered spends until they're flushed, so a fleet can over-allow by up to
+///   one `flush_interval` of traffic per node
+/// - Buffered spends are lost if the process dies before flushing them
+/// - A write that moves `last_updated`, from a read of the key taken while a flush of it is in
+///   flight, can count the flushed deltas twice or not at all. Spends can't, they're recorded
+///   against the view they were worked out from
+///
+/// Clones share their buffer, so one clone can be handed to a `TokenBucket` and another to `run`.
+pub struct BufferedClient<C> {
+    pub inner: Arc<C>,
+    /// How often `run` flushes, `DEFAULT_FLUSH_INTERVAL` by default
+    pub flush_interval: Duration,
+    buffer: Arc<Mutex<HashMap<String, Buffered>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<C> Clone for BufferedClient<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            flush_interval: self.flush_interval,
+            buffer: self.buffer.clone(),
+            stopped: self.stopped.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Buffered {
+    /// The bucket as this client last returned or accepted it, unflushed deltas included
+    view: Option<RateLimitItem>,
+    /// The net change not yet flushed to `inner`
+    delta: i128,
+}
+
+impl<C: TokenBucketClient> BufferedClient<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            buffer: Arc::default(),
+            stopped: Arc::default(),
+        }
+    }
+
+    fn buffer(&self) -> MutexGuard<'_, HashMap<String, Buffered>> {
+        lock(&self.buffer)
+    }
+
+    /// Flush every buffered delta to `inner`
+    /// Deltas that fail to flush are kept for the next flush, the last error is returned
+    pub async fn flush(&self) -> Result<(), C::Error> {
+        let deltas: Vec<(String, i128)> = {
+            let mut buffer = self.buffer();
+            // Keys with nothing to flush are forgotten. The rest keep their view, so a spend
+            // from a view read before this flush is still buffered while the flush is in flight
+            buffer.retain(|_, buffered| buffered.delta != 0);
+            buffer
+                .iter_mut()
+                .map(|(id, buffered)| (id.clone(), mem::take(&mut buffered.delta)))
+                .collect()
+        };
+
+        let mut result = Ok(());
+        for (id, delta) in deltas {
+            let tokens = u64::try_from(delta.unsigned_abs()).unwrap_or(u64::MAX);
+            let flushed = if delta > 0 {
+                self.inner.refund(&id, tokens).await
+            } else {
+                self.inner.spend(&id, tokens).await
+            };
+            if let Err(error) = flushed {
+                self.buffer()
+                    .entry(id)
+                    .or_insert(Buffered {
+                        view: None,
+                        delta: 0,
+                    })
+                    .delta += delta;
+                result = Err(error);
+            }
+        }
+        result
+    }
+
+    /// Flush every `flush_interval` until `shutdown` is called, then flush one last time
+    /// Spawn this on the runtime with `sleep` from it, e.g. the SDK's default `TokioSleep`
+    pub async fn run(&self, sleep: impl AsyncSleep) -> Result<(), C::Error> {
+        while !self.stopped.load(Ordering::Relaxed) {
+            sleep.sleep(self.flush_interval).await;
+            // Deltas that fail to flush stay buffered for the next one
+            if let Err(_error) = self.flush().await {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("failed to flush buffered rate limit spends");
+            }
+        }
+        self.flush().await
+    }
+
+    /// Stop `run` after its current wait, it flushes everything buffered before returning
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<C: TokenBucketClient + Send> TokenBucketClient for BufferedClient<C> {
+    type Error = C::Error;
+
+    async fn get_raw(
+        &self,
+        id: &str,
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        let (limit, settings) = self.inner.get_raw(id).await?;
+        let mut buffer = self.buffer();
+        let buffered = buffer.entry(id.into()).or_insert(Buffered {
+            view: None,
+            delta: 0,
+        });
+        let limit = limit.map(|mut limit| {
+            let tokens = i128::from(limit.tokens) + buffered.delta;
+            limit.tokens = u64::try_from(tokens.max(0)).unwrap_or(u64::MAX);
+            limit
+        });
+        buffered.view = limit;
+        Ok((limit, settings))
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
+        {
+            let mut buffer = self.buffer();
+            if let Some(buffered) = buffer.get_mut(id) {
+                if let Some(view) = buffered.view.filter(|view| {
+                    view.last_updated == limit.last_updated
+                        && view.cap == limit.cap
+                        && view.fraction == limit.fraction
+                }) {
+                    buffered.delta += i128::from(limit.tokens) - i128::from(view.tokens);
+                    buffered.view = Some(limit);
+                    return Ok(PutOutcome::Written);
+                }
+            }
+        }
+
+        let outcome = self.inner.put_limit(id, limit).await?;
+        if outcome == PutOutcome::Written {
+            // The written tokens were worked out from a view that included the buffered delta
+            self.buffer().insert(
+                id.into(),
+                Buffered {
+                    view: Some(limit),
+                    delta: 0,
+                },
+            );
+        }
+        Ok(outcome)
+    }
+
+    async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
+        self.inner.put_settings(id, settings).await
+    }
+
+    async fn refund(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.inner.refund(id, tokens).await
+    }
+
+    async fn spend(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.inner.spend(id, tokens).await
+    }
+
+    async fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> Result<bool, Self::Error> {
+        self.inner.compare_and_put_settings(id, expected, new).await
+    }
+
+    async fn get_sub_buckets(&self, id: &str) -> Result<Vec<(String, RateLimitItem)>, Self::Error> {
+        self.inner.get_sub_buckets(id).await
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        self.inner.get_settings(id).await
+    }
+
+    async fn get_settings_many(
+        &self,
+        ids: &[&str],
+    ) -> Result<Vec<Option<RateLimitSettings>>, Self::Error> {
+        self.inner.get_settings_many(ids).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_support::{settings, FlakyClient, TokioSleep},
+        InMemoryClient, LimitResult, TokenBucket,
+    };
+
+    #[tokio::test]
+    async fn flush_reconciles_buffered_spends() {
+        let inner = InMemoryClient::new();
+        let client = BufferedClient::new(inner.clone());
+        let bucket = TokenBucket::new(client.clone(), settings(10, 10, 1, 60)).unwrap();
+        let t0 = 1_000_000;
+        let stored = || async { inner.get_raw("a").await.unwrap().0.unwrap().tokens };
+
+        // The first write seeds the bucket, the spends after it are only buffered
+        for remaining in (5..10).rev() {
+            assert_eq!(
+                bucket.limit_at("a", 1, t0).await.unwrap(),
+                LimitResult::Allow { remaining }
+            );
+        }
+        assert_eq!(stored().await, 9);
+        bucket.refund("a", 2).await.unwrap();
+        assert_eq!(stored().await, 11);
+
+        client.flush().await.unwrap();
+        assert_eq!(stored().await, 7);
+        // A refill moves last_updated, so it's written straight through
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 60).await.unwrap(),
+            LimitResult::Allow { remaining: 7 }
+        );
+        assert_eq!(stored().await, 7);
+    }
+
+    #[tokio::test]
+    async fn run_flushes_on_shutdown() {
+        let inner = InMemoryClient::new();
+        let mut client = BufferedClient::new(inner.clone());
+        client.flush_interval = Duration::from_millis(1);
+        let bucket = TokenBucket::new(client.clone(), settings(10, 10, 1, 60)).unwrap();
+
+        let (ran, ()) = tokio::join!(client.run(TokioSleep), async {
+            for _ in 0..3 {
+                bucket.limit_at("a", 1, 1_000_000).await.unwrap();
+            }
+            client.shutdown();
+        });
+        ran.unwrap();
+        let (limit, _) = inner.get_raw("a").await.unwrap();
+        assert_eq!(limit.unwrap().tokens, 7);
+    }
+
+    #[tokio::test]
+    async fn spends_during_a_flush_stay_buffered() {
+        let inner = FlakyClient::default();
+        let client = BufferedClient::new(inner.clone());
+        let limit = |tokens| RateLimitItem {
+            last_updated: 1_000_000,
+            tokens,
+            cap: None,
+            fraction: 0,
+        };
+        client.put_limit("a", limit(10)).await.unwrap();
+        client.put_limit("a", limit(9)).await.unwrap();
+
+        // Read before the flush and spent from while it's in flight
+        client.get_raw("a").await.unwrap();
+        inner.delay_by(Duration::from_millis(100));
+        let (flushed, spent) = tokio::join!(client.flush(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.put_limit("a", limit(8)).await
+        });
+        flushed.unwrap();
+        assert_eq!(spent.unwrap(), PutOutcome::Written);
+
+        inner.delay_by(Duration::ZERO);
+        client.flush().await.unwrap();
+        let (stored, _) = inner.get_raw("a").await.unwrap();
+        assert_eq!(stored.unwrap().tokens, 8);
+    }
+}
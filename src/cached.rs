@@ -0,0 +1,301 @@
+use crate::{
+    lock, PutOutcome, RateLimitItem, RateLimitSettings, TokenBucketClient, TokenBucketError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// The default for `CachedSettingsClient::ttl`
+pub const DEFAULT_SETTINGS_TTL: Duration = Duration::from_secs(60);
+
+/// The snapshot format `CachedSettingsClient::dump_snapshot` writes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+/// Caches the settings `inner` returns in process, for up to `ttl`
+///
+/// `get_settings` and `get_settings_many` are answered from the cache where they can be.
+/// `get_raw` always goes to `inner`, which reads the limit and settings together on DynamoDB,
+/// and refreshes the cached settings with what it read. Everything else passes through
+pub struct CachedSettingsClient<C> {
+    pub inner: C,
+    /// How long a cached entry is used before `inner` is asked again, `DEFAULT_SETTINGS_TTL` by default
+    pub ttl: Duration,
+    cache: Mutex<HashMap<String, CachedSettings>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedSettings {
+    settings: Option<RateLimitSettings>,
+    cached_at: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    settings: HashMap<String, RateLimitSettings>,
+}
+
+impl<C> CachedSettingsClient<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            ttl: DEFAULT_SETTINGS_TTL,
+            cache: Mutex::default(),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<String, CachedSettings>> {
+        lock(&self.cache)
+    }
+
+    /// The cached settings for `id` if there's a fresh entry, `Some(None)` if `id` has none
+    fn cached(&self, id: &str) -> Option<Option<RateLimitSettings>> {
+        self.cache()
+            .get(id)
+            .filter(|entry| entry.cached_at.elapsed() < self.ttl)
+            .map(|entry| entry.settings)
+    }
+
+    fn insert(&self, id: &str, settings: Option<RateLimitSettings>) {
+        self.cache().insert(
+            id.into(),
+            CachedSettings {
+                settings,
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Add every entry of a snapshot written by `dump_snapshot` to the cache, returning how many
+    /// there were. Loaded entries expire after `ttl` like any other
+    ///
+    /// A snapshot only answers `get_settings` and `get_settings_many`. `limit` reads through
+    /// `get_raw`, which still goes to `inner` for the limit, so loading a snapshot doesn't save
+    /// those reads
+    ///
+    /// ```
+    /// use distributed_ratelimit::{CachedSettingsClient, InMemoryClient};
+    ///
+    /// let snapshot = r#"{"version":1,"settings":{"user#1":
+    ///     {"max_tokens":10,"starting_tokens":10,"refill_rate":1,"refill_interval":60}}}"#;
+    /// let client = CachedSettingsClient::new(InMemoryClient::new());
+    /// assert_eq!(client.load_snapshot(snapshot.as_bytes()).unwrap(), 1);
+    ///
+    /// let mut dumped = Vec::new();
+    /// client.dump_snapshot(&mut dumped).unwrap();
+    /// let reloaded = CachedSettingsClient::new(InMemoryClient::new());
+    /// assert_eq!(reloaded.load_snapshot(dumped.as_slice()).unwrap(), 1);
+    /// ```
+    pub fn load_snapshot(&self, reader: impl Read) -> Result<usize, TokenBucketError> {
+        let snapshot: Snapshot = serde_json::from_reader(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(TokenBucketError::SnapshotVersion(snapshot.version));
+        }
+        let cached_at = Instant::now();
+        let loaded = snapshot.settings.len();
+        self.cache()
+            .extend(snapshot.settings.into_iter().map(|(id, settings)| {
+                let settings = Some(settings);
+                (
+                    id,
+                    CachedSettings {
+                        settings,
+                        cached_at,
+                    },
+                )
+            }));
+        Ok(loaded)
+    }
+
+    /// Write every cached id that has settings as a versioned JSON snapshot, expired entries included
+    pub fn dump_snapshot(&self, writer: impl Write) -> Result<(), TokenBucketError> {
+        let settings = self
+            .cache()
+            .iter()
+            .filter_map(|(id, entry)| Some((id.clone(), entry.settings?)))
+            .collect();
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            settings,
+        };
+        serde_json::to_writer(writer, &snapshot)?;
+        Ok(())
+    }
+}
+
+impl<C: TokenBucketClient> TokenBucketClient for CachedSettingsClient<C> {
+    type Error = C::Error;
+
+    async fn get_raw(
+        &self,
+        id: &str,
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        let (limit, settings) = self.inner.get_raw(id).await?;
+        self.insert(id, settings);
+        Ok((limit, settings))
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
+        self.inner.put_limit(id, limit).await
+    }
+
+    async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
+        self.inner.put_settings(id, settings).await?;
+        self.insert(id, Some(settings));
+        Ok(())
+    }
+
+    async fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> Result<bool, Self::Error> {
+        let swapped = self
+            .inner
+            .compare_and_put_settings(id, expected, new)
+            .await?;
+        if swapped {
+            self.insert(id, Some(new));
+        } else {
+            // The cached settings were what the caller expected, so they're probably out of date
+            self.cache().remove(id);
+        }
+        Ok(swapped)
+    }
+
+    async fn refund(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.inner.refund(id, tokens).await
+    }
+
+    async fn spend(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.inner.spend(id, tokens).await
+    }
+
+    async fn get_sub_buckets(&self, id: &str) -> Result<Vec<(String, RateLimitItem)>, Self::Error> {
+        self.inner.get_sub_buckets(id).await
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        if let Some(settings) = self.cached(id) {
+            return Ok(settings);
+        }
+        let settings = self.inner.get_settings(id).await?;
+        self.insert(id, settings);
+        Ok(settings)
+    }
+
+    async fn get_settings_many(
+        &self,
+        ids: &[&str],
+    ) -> Result<Vec<Option<RateLimitSettings>>, Self::Error> {
+        let mut settings: Vec<_> = ids.iter().map(|id| self.cached(id)).collect();
+        let missing: Vec<&str> = ids
+            .iter()
+            .zip(&settings)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        if !missing.is_empty() {
+            let mut fetched = self.inner.get_settings_many(&missing).await?.into_iter();
+            for (id, cached) in ids.iter().zip(&mut settings) {
+                if cached.is_none() {
+                    let value = fetched.next().flatten();
+                    self.insert(id, value);
+                    *cached = Some(value);
+                }
+            }
+        }
+        Ok(settings.into_iter().map(Option::flatten).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{full_settings, FlakyClient};
+    use crate::InMemoryClient;
+
+    #[tokio::test]
+    async fn get_settings_many_mixes_cached_and_fetched() {
+        let client = CachedSettingsClient::new(InMemoryClient::new());
+        client
+            .inner
+            .put_settings("a", full_settings(1))
+            .await
+            .unwrap();
+        client
+            .inner
+            .put_settings("c", full_settings(3))
+            .await
+            .unwrap();
+        // Cache "a", then change it behind the cache's back
+        assert_eq!(
+            client.get_settings("a").await.unwrap(),
+            Some(full_settings(1))
+        );
+        client
+            .inner
+            .put_settings("a", full_settings(10))
+            .await
+            .unwrap();
+
+        let many = client.get_settings_many(&["c", "b", "a"]).await.unwrap();
+        assert_eq!(many, [Some(full_settings(3)), None, Some(full_settings(1))]);
+
+        // Every id is cached now, absent ones included
+        client
+            .inner
+            .put_settings("b", full_settings(2))
+            .await
+            .unwrap();
+        client
+            .inner
+            .put_settings("c", full_settings(30))
+            .await
+            .unwrap();
+        let many = client.get_settings_many(&["a", "b", "c"]).await.unwrap();
+        assert_eq!(many, [Some(full_settings(1)), None, Some(full_settings(3))]);
+    }
+
+    #[tokio::test]
+    async fn loaded_snapshots_answer_without_inner() {
+        let inner = FlakyClient::default();
+        let client = CachedSettingsClient::new(inner.clone());
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION,
+            "settings": {"a": full_settings(5)},
+        });
+        let loaded = client.load_snapshot(snapshot.to_string().as_bytes());
+        assert_eq!(loaded.unwrap(), 1);
+
+        inner.fail_with(|| TokenBucketError::Timeout(Duration::from_secs(1)));
+        assert_eq!(
+            client.get_settings("a").await.unwrap(),
+            Some(full_settings(5))
+        );
+        assert!(client.get_settings("b").await.is_err());
+        // The limit is still read from inner
+        assert!(client.get_raw("a").await.is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_snapshot_versions() {
+        let client = CachedSettingsClient::new(InMemoryClient::new());
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION + 1,
+            "settings": {"a": full_settings(5)},
+        });
+        let loaded = client.load_snapshot(snapshot.to_string().as_bytes());
+        assert!(matches!(
+            loaded,
+            Err(TokenBucketError::SnapshotVersion(version)) if version == SNAPSHOT_VERSION + 1
+        ));
+        assert!(client.cache().is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+//! Rate limit keys for IP networks, so every address in a subnet shares one bucket
+//!
+//! Keys are the network in CIDR notation, e.g. `203.0.113.0/24` or `2001:db8::/64`.
+
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The network key for `ip` with a `prefix_len` bit prefix
+/// Prefixes longer than the address are clamped to it. An IPv4-mapped IPv6 address
+/// (`::ffff:203.0.113.7`) is keyed as its IPv4 address, with the part of the prefix past the
+/// 96 bit mapping, so /120 keys it by its IPv4 /24. A prefix shorter than 96 says nothing about
+/// how wide an IPv4 network should be, so those are keyed with a full /32 rather than all
+/// sharing one bucket. Use `CidrKey` to pick the IPv4 prefix of a dual-stack listener directly
+pub fn network_key(ip: IpAddr, prefix_len: u8) -> String {
+    match ip {
+        IpAddr::V4(ip) => v4_key(ip, prefix_len),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => v4_key(v4, prefix_len.checked_sub(96).unwrap_or(32)),
+            None => v6_key(ip, prefix_len),
+        },
+    }
+}
+
+/// Parse `ip` and return its network key, see `network_key`
+/// Accepts IPv6 zone ids (`fe80::1%eth0`) and brackets (`[2001:db8::1]`), neither is part of the key
+pub fn parse_network_key(ip: &str, prefix_len: u8) -> Result<String, AddrParseError> {
+    Ok(network_key(parse_ip(ip)?, prefix_len))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Network keys for mixed IPv4 and IPv6 traffic, each with its own prefix length
+/// e.g. `CidrKey::new(24, 64)` keys IPv4 by /24 and IPv6 by /64
+pub struct CidrKey {
+    pub v4_prefix: u8,
+    pub v6_prefix: u8,
+}
+
+impl CidrKey {
+    pub const fn new(v4_prefix: u8, v6_prefix: u8) -> Self {
+        Self {
+            v4_prefix,
+            v6_prefix,
+        }
+    }
+
+    /// The network key for `ip`, IPv4-mapped IPv6 addresses use `v4_prefix`
+    pub fn key(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => v4_key(ip, self.v4_prefix),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(v4) => v4_key(v4, self.v4_prefix),
+                None => v6_key(ip, self.v6_prefix),
+            },
+        }
+    }
+
+    /// Parse `ip` and return its network key, see `parse_network_key` for the accepted forms
+    pub fn parse_key(&self, ip: &str) -> Result<String, AddrParseError> {
+        Ok(self.key(parse_ip(ip)?))
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr, AddrParseError> {
+    let ip = ip.trim();
+    let ip = ip
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(ip);
+    let ip = ip.split_once('%').map_or(ip, |(ip, _zone)| ip);
+    ip.parse()
+}
+
+fn v4_key(ip: Ipv4Addr, prefix_len: u8) -> String {
+    let prefix_len = prefix_len.min(32);
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0);
+    format!("{}/{prefix_len}", Ipv4Addr::from(u32::from(ip) & mask))
+}
+
+fn v6_key(ip: Ipv6Addr, prefix_len: u8) -> String {
+    let prefix_len = prefix_len.min(128);
+    let mask = u128::MAX
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or(0);
+    format!("{}/{prefix_len}", Ipv6Addr::from(u128::from(ip) & mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_networks() {
+        let key = |ip: &str, prefix_len| parse_network_key(ip, prefix_len).unwrap();
+        assert_eq!(key("203.0.113.7", 24), "203.0.113.0/24");
+        assert_eq!(key("203.0.113.7", 32), "203.0.113.7/32");
+        assert_eq!(key("203.0.113.7", 0), "0.0.0.0/0");
+        assert_eq!(key("203.0.113.7", 64), "203.0.113.7/32");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 64), "2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 48), "2001:db8:1::/48");
+        assert_eq!(key("2001:db8::1", 200), "2001:db8::1/128");
+    }
+
+    #[test]
+    fn strips_zone_ids_and_brackets() {
+        assert_eq!(parse_network_key("fe80::1%eth0", 64).unwrap(), "fe80::/64");
+        assert_eq!(
+            parse_network_key("[2001:db8::1]", 64).unwrap(),
+            "2001:db8::/64"
+        );
+        assert_eq!(
+            parse_network_key(" 203.0.113.7 ", 24).unwrap(),
+            "203.0.113.0/24"
+        );
+        assert!(parse_network_key("not an ip", 24).is_err());
+    }
+
+    #[test]
+    fn keys_mapped_addresses_as_ipv4() {
+        // A /64 meant for IPv6 clients must not put every IPv4 client in one bucket
+        let mapped = parse_network_key("::ffff:203.0.113.7", 64).unwrap();
+        assert_eq!(mapped, "203.0.113.7/32");
+        assert_ne!(
+            parse_network_key("::ffff:198.51.100.1", 64).unwrap(),
+            mapped
+        );
+
+        // Past the mapping, the prefix applies to the IPv4 address
+        assert_eq!(
+            parse_network_key("::ffff:203.0.113.7", 120).unwrap(),
+            "203.0.113.0/24"
+        );
+        assert_eq!(
+            parse_network_key("::ffff:203.0.113.7", 96).unwrap(),
+            "0.0.0.0/0"
+        );
+
+        let cidr = CidrKey::new(24, 64);
+        assert_eq!(
+            cidr.parse_key("::ffff:203.0.113.7").unwrap(),
+            "203.0.113.0/24"
+        );
+        assert_eq!(cidr.parse_key("203.0.113.9").unwrap(), "203.0.113.0/24");
+        assert_eq!(cidr.parse_key("2001:db8::1").unwrap(), "2001:db8::/64");
+    }
+}
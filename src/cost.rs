@@ -0,0 +1,52 @@
+use std::num::NonZeroU64;
+
+/// Maps the size of a request in bytes to the tokens it costs, see `TokenBucket::limit_sized`
+pub trait CostPolicy {
+    fn cost(&self, bytes: u64) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// One token per `bytes_per_token` bytes, rounding a partial chunk up, and never less than `min_tokens`
+///
+/// ```
+/// use distributed_ratelimit::{CostPolicy, PerBytes};
+/// use std::num::NonZeroU64;
+///
+/// let per_kb = PerBytes::new(NonZeroU64::new(1024).unwrap(), 1);
+/// assert_eq!(per_kb.cost(0), 1);
+/// assert_eq!(per_kb.cost(1), 1);
+/// assert_eq!(per_kb.cost(1024), 1);
+/// assert_eq!(per_kb.cost(1025), 2);
+/// assert_eq!(per_kb.cost(u64::MAX), u64::MAX.div_ceil(1024));
+/// ```
+pub struct PerBytes {
+    pub bytes_per_token: NonZeroU64,
+    pub min_tokens: u64,
+}
+
+impl PerBytes {
+    pub const fn new(bytes_per_token: NonZeroU64, min_tokens: u64) -> Self {
+        Self {
+            bytes_per_token,
+            min_tokens,
+        }
+    }
+}
+
+impl CostPolicy for PerBytes {
+    fn cost(&self, bytes: u64) -> u64 {
+        bytes
+            .div_ceil(self.bytes_per_token.get())
+            .max(self.min_tokens)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The same cost regardless of size
+pub struct Fixed(pub u64);
+
+impl CostPolicy for Fixed {
+    fn cost(&self, _bytes: u64) -> u64 {
+        self.0
+    }
+}
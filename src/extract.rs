@@ -0,0 +1,260 @@
+//! An axum extractor that rate limits the handlers taking it
+//!
+//! The bucket is read from the router state as an `Arc<TokenBucket>`, through `FromRef` when the
+//! state holds more than the bucket, and the key from the request by a `RateLimitKey`.
+//! A deny rejects the request with a 429 and a `Retry-After` header, or with the response built by
+//! a `DenyResponse` added to the request extensions, e.g. with an `Extension` layer.
+//!
+//! ```no_run
+//! use axum::{http::request::Parts, routing::get, Router};
+//! use distributed_ratelimit::{
+//!     extract::{RateLimit, RateLimitKey},
+//!     InMemoryClient, TokenBucket,
+//! };
+//! use std::sync::Arc;
+//!
+//! /// Limits each API key separately
+//! struct ApiKey;
+//!
+//! impl<S> RateLimitKey<S> for ApiKey {
+//!     fn key(parts: &Parts, _state: &S) -> String {
+//!         let key = parts.headers.get("x-api-key").and_then(|key| key.to_str().ok());
+//!         format!("api:{}", key.unwrap_or("anonymous"))
+//!     }
+//! }
+//!
+//! async fn handler(limit: RateLimit<ApiKey, InMemoryClient>) -> String {
+//!     format!("{} requests left", limit.remaining)
+//! }
+//!
+//! # fn app(bucket: Arc<TokenBucket<InMemoryClient>>) -> Router {
+//! Router::new().route("/", get(handler)).with_state(bucket)
+//! # }
+//! ```
+
+use crate::{
+    headers, LimitResult, LinearRefill, RefillPolicy, TokenBucket, TokenBucketClient,
+    TokenBucketError,
+};
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{
+    fmt::{self, Display},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+/// Picks the bucket a request is limited by
+pub trait RateLimitKey<S> {
+    /// The tokens each request costs
+    const COST: u64 = 1;
+
+    fn key(parts: &Parts, state: &S) -> String;
+}
+
+/// Limits the request by `K::COST` on the key `K` picks, rejecting it if denied
+pub struct RateLimit<K, T: TokenBucketClient, R: RefillPolicy = LinearRefill> {
+    /// The tokens left after this request
+    pub remaining: u64,
+    _key: PhantomData<fn() -> K>,
+    _bucket: PhantomData<fn() -> TokenBucket<T, R>>,
+}
+
+#[derive(Clone)]
+/// Builds the response `RateLimit` rejects denied requests with
+///
+/// ```
+/// use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+/// use distributed_ratelimit::{extract::DenyResponse, LimitResult};
+///
+/// let deny = DenyResponse::new(|result: &LimitResult| {
+///     let body = serde_json::json!({
+///         "error": "rate_limited",
+///         "retry_after": result.retry_after(),
+///     });
+///     (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response()
+/// });
+/// // Added to a router with `.layer(Extension(deny))`
+/// # let _ = Extension(deny);
+/// ```
+pub struct DenyResponse(Arc<dyn Fn(&LimitResult) -> Response + Send + Sync>);
+
+impl DenyResponse {
+    pub fn new(build: impl Fn(&LimitResult) -> Response + Send + Sync + 'static) -> Self {
+        Self(Arc::new(build))
+    }
+
+    pub fn build(&self, result: &LimitResult) -> Response {
+        (self.0)(result)
+    }
+}
+
+impl Default for DenyResponse {
+    /// A 429, with a `Retry-After` header unless the request can never be allowed
+    fn default() -> Self {
+        Self::new(|result| match result {
+            LimitResult::Deny {
+                retry_after,
+                recoverable: true,
+                ..
+            } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(
+                    header::RETRY_AFTER,
+                    headers::retry_after_delta_seconds(*retry_after),
+                )],
+            )
+                .into_response(),
+            _ => StatusCode::TOO_MANY_REQUESTS.into_response(),
+        })
+    }
+}
+
+impl fmt::Debug for DenyResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DenyResponse").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+/// Why `RateLimit` rejected a request
+pub enum RateLimitRejection<E> {
+    /// The request was denied, answered with the response its `DenyResponse` built
+    Denied(Response),
+    /// A 500, the backend couldn't be reached or the key was invalid
+    Backend(E),
+}
+
+impl<E: Display> IntoResponse for RateLimitRejection<E> {
+    fn into_response(self) -> Response {
+        match self {
+            RateLimitRejection::Denied(response) => response,
+            RateLimitRejection::Backend(_error) => {
+                #[cfg(feature = "tracing")]
+                tracing::error!(error = %_error, "rate limit failed");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+impl<S, K, T, R> FromRequestParts<S> for RateLimit<K, T, R>
+where
+    S: Send + Sync,
+    K: RateLimitKey<S>,
+    T: TokenBucketClient + Send + 'static,
+    T::Error: From<TokenBucketError> + Display,
+    R: RefillPolicy + Send + Sync + 'static,
+    Arc<TokenBucket<T, R>>: FromRef<S>,
+{
+    type Rejection = RateLimitRejection<T::Error>;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let bucket = Arc::<TokenBucket<T, R>>::from_ref(state);
+        let key = K::key(parts, state);
+        match bucket.limit(&key, K::COST).await {
+            Ok(LimitResult::Allow { remaining }) => Ok(Self {
+                remaining,
+                _key: PhantomData,
+                _bucket: PhantomData,
+            }),
+            Ok(result) => {
+                let response = match parts.extensions.get::<DenyResponse>() {
+                    Some(deny) => deny.build(&result),
+                    None => DenyResponse::default().build(&result),
+                };
+                Err(RateLimitRejection::Denied(response))
+            }
+            Err(error) => Err(RateLimitRejection::Backend(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::settings, InMemoryClient};
+    use axum::{body::Body, http::Request, routing::get, Extension, Json, Router};
+    use tower::ServiceExt;
+
+    /// Limits every request on one shared key
+    struct Everyone;
+
+    impl<S> RateLimitKey<S> for Everyone {
+        fn key(_parts: &Parts, _state: &S) -> String {
+            "everyone".into()
+        }
+    }
+
+    async fn handler(limit: RateLimit<Everyone, InMemoryClient>) -> String {
+        format!("{} requests left", limit.remaining)
+    }
+
+    fn app() -> Router {
+        let bucket = TokenBucket::new(InMemoryClient::new(), settings(2, 2, 1, 60)).unwrap();
+        Router::new()
+            .route("/", get(handler))
+            .with_state(Arc::new(bucket))
+    }
+
+    async fn get_root(app: &Router) -> Response {
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn allows_with_the_remaining_tokens() {
+        let app = app();
+        for remaining in [1, 0] {
+            let response = get_root(&app).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, format!("{remaining} requests left"));
+        }
+    }
+
+    #[tokio::test]
+    async fn denies_with_retry_after() {
+        let app = app();
+        get_root(&app).await;
+        get_root(&app).await;
+
+        let denied = get_root(&app).await;
+        assert_eq!(denied.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = denied.headers()[header::RETRY_AFTER].to_str().unwrap();
+        let retry_after: u64 = retry_after.parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+    }
+
+    #[tokio::test]
+    async fn denies_with_the_extension_response() {
+        let deny = DenyResponse::new(|result: &LimitResult| {
+            let body = serde_json::json!({
+                "error": "rate_limited",
+                "recoverable": matches!(result, LimitResult::Deny { recoverable: true, .. }),
+            });
+            (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+        });
+        let app = app().layer(Extension(deny));
+        get_root(&app).await;
+        get_root(&app).await;
+
+        let denied = get_root(&app).await;
+        assert_eq!(denied.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!denied.headers().contains_key(header::RETRY_AFTER));
+        let body: serde_json::Value = serde_json::from_str(&body(denied).await).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "rate_limited", "recoverable": true})
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    current_unix_time, InMemoryClient, PutOutcome, RateLimitItem, RateLimitSettings,
+    TokenBucketClient, TransientError,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The default for `FallbackClient::recovery_interval`
+pub const DEFAULT_RECOVERY_INTERVAL: u64 = 30;
+
+#[derive(Debug, Clone)]
+/// Limits with `primary` while it is healthy and with a local `fallback` while it isn't
+///
+/// When a `get` or `put_limit` on the primary fails with an error `is_transient` accepts, the call
+/// is served by the fallback and the client stays on the fallback for `recovery_interval` seconds
+/// before trying the primary again. Other errors are returned without failing over.
+/// This bounds the blast radius of a backend outage better than failing open.
+///
+/// While degraded every node limits on its own:
+/// - Every key uses `fallback_settings`, per-key settings stored in the primary can't be read
+/// - A fleet of N nodes allows up to N times `fallback_settings` in total.
+///   `RateLimitSettings::per_node` splits a global limit into a per-node one
+/// - Traffic for a key that isn't spread evenly is over-limited, since the node receiving most of it
+///   only holds its share of the budget
+/// - Tokens spent on the fallback aren't written back, keys resume with their primary bucket as it
+///   was before the outage
+///
+/// Settings reads and writes always go to the primary and return its errors.
+pub struct FallbackClient<P: TokenBucketClient, F: TokenBucketClient = InMemoryClient> {
+    pub primary: P,
+    pub fallback: F,
+    /// The settings used for every key while on the fallback
+    pub fallback_settings: RateLimitSettings,
+    /// The number of seconds to stay on the fallback after the primary fails
+    pub recovery_interval: u64,
+    /// Whether a primary error is an outage to fail over on, rather than one to return as is.
+    /// `TransientError::is_transient` by default
+    pub is_transient: fn(&P::Error) -> bool,
+    /// Unix time until which calls go straight to the fallback
+    degraded_until: Arc<AtomicU64>,
+}
+
+impl<P: TokenBucketClient> FallbackClient<P>
+where
+    P::Error: TransientError,
+{
+    pub fn new(primary: P, fallback_settings: RateLimitSettings) -> Self {
+        Self {
+            primary,
+            fallback: InMemoryClient::new(),
+            fallback_settings,
+            recovery_interval: DEFAULT_RECOVERY_INTERVAL,
+            is_transient: P::Error::is_transient,
+            degraded_until: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl<P: TokenBucketClient, F: TokenBucketClient> FallbackClient<P, F>
+where
+    P::Error: From<F::Error>,
+{
+    /// Whether calls are currently being served by the fallback
+    pub fn is_degraded(&self) -> bool {
+        current_unix_time() < self.degraded_until.load(Ordering::Relaxed)
+    }
+
+    fn degrade(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            recovery_interval = self.recovery_interval,
+            "rate limit backend failed, switching to the fallback"
+        );
+        self.degraded_until.store(
+            current_unix_time().saturating_add(self.recovery_interval),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Run `primary` unless degraded, switching to `fallback` if it fails
+    async fn with_fallback<O>(
+        &self,
+        primary: impl Future<Output = Result<O, P::Error>>,
+        fallback: impl Future<Output = Result<O, F::Error>>,
+    ) -> Result<O, P::Error> {
+        if !self.is_degraded() {
+            match primary.await {
+                Ok(output) => return Ok(output),
+                Err(error) if !(self.is_transient)(&error) => return Err(error),
+                Err(_) => self.degrade(),
+            }
+        }
+        Ok(fallback.await?)
+    }
+}
+
+impl<P: TokenBucketClient, F: TokenBucketClient> TokenBucketClient for FallbackClient<P, F>
+where
+    P::Error: From<F::Error>,
+{
+    type Error = P::Error;
+
+    async fn get_raw(
+        &self,
+        id: &str,
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        self.with_fallback(self.primary.get_raw(id), async {
+            let (limit, _) = self.fallback.get_raw(id).await?;
+            Ok((limit, Some(self.fallback_settings)))
+        })
+        .await
+    }
+
+    async fn get(
+        &self,
+        id: &str,
+        default_settings: RateLimitSettings,
+    ) -> Result<(RateLimitItem, RateLimitSettings), Self::Error> {
+        self.with_fallback(
+            self.primary.get(id, default_settings),
+            self.fallback.get(id, self.fallback_settings),
+        )
+        .await
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
+        self.with_fallback(
+            self.primary.put_limit(id, limit),
+            self.fallback.put_limit(id, limit),
+        )
+        .await
+    }
+
+    async fn refund(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.with_fallback(
+            self.primary.refund(id, tokens),
+            self.fallback.refund(id, tokens),
+        )
+        .await
+    }
+
+    async fn spend(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        self.with_fallback(
+            self.primary.spend(id, tokens),
+            self.fallback.spend(id, tokens),
+        )
+        .await
+    }
+
+    async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
+        self.primary.put_settings(id, settings).await
+    }
+
+    async fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> Result<bool, Self::Error> {
+        self.primary
+            .compare_and_put_settings(id, expected, new)
+            .await
+    }
+
+    async fn get_sub_buckets(&self, id: &str) -> Result<Vec<(String, RateLimitItem)>, Self::Error> {
+        self.primary.get_sub_buckets(id).await
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        self.primary.get_settings(id).await
+    }
+
+    async fn get_settings_many(
+        &self,
+        ids: &[&str],
+    ) -> Result<Vec<Option<RateLimitSettings>>, Self::Error> {
+        self.primary.get_settings_many(ids).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_support::{settings, FlakyClient},
+        LimitResult, TokenBucket, TokenBucketError,
+    };
+    use std::time::Duration;
+
+    fn timeout() -> TokenBucketError {
+        TokenBucketError::Timeout(Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_transient_errors() {
+        let primary = FlakyClient::default();
+        primary.fail_with(timeout);
+        let client = FallbackClient::new(primary.clone(), settings(2, 2, 1, 60));
+        let bucket = TokenBucket::new(client, settings(100, 100, 1, 60)).unwrap();
+
+        // Limited with the fallback settings rather than failing
+        assert_eq!(
+            bucket.limit("a", 1).await.unwrap(),
+            LimitResult::Allow { remaining: 1 }
+        );
+        assert!(bucket.client.is_degraded());
+    }
+
+    #[tokio::test]
+    async fn stays_degraded_for_the_recovery_interval() {
+        let primary = FlakyClient::default();
+        primary.fail_with(timeout);
+        let client = FallbackClient::new(primary.clone(), settings(2, 2, 1, 60));
+        let bucket = TokenBucket::new(client, settings(100, 100, 1, 60)).unwrap();
+        bucket.limit("a", 1).await.unwrap();
+
+        // The primary is back, but isn't tried again until the interval is over
+        primary.recover();
+        assert_eq!(
+            bucket.limit("a", 1).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+        assert!(bucket.client.is_degraded());
+        assert_eq!(primary.inner.get_raw("a").await.unwrap(), (None, None));
+
+        let mut client = FallbackClient::new(primary.clone(), settings(2, 2, 1, 60));
+        client.recovery_interval = 0;
+        let bucket = TokenBucket::new(client, settings(100, 100, 1, 60)).unwrap();
+        primary.fail_with(timeout);
+        bucket.limit("b", 1).await.unwrap();
+        primary.recover();
+        assert_eq!(
+            bucket.limit("b", 1).await.unwrap(),
+            LimitResult::Allow { remaining: 99 }
+        );
+        assert!(!bucket.client.is_degraded());
+    }
+
+    #[tokio::test]
+    async fn passes_permanent_errors_through() {
+        let primary = FlakyClient::default();
+        primary.fail_with(|| TokenBucketError::InvalidKey("bad".into()));
+        let client = FallbackClient::new(primary, settings(2, 2, 1, 60));
+        let bucket = TokenBucket::new(client, settings(100, 100, 1, 60)).unwrap();
+
+        let result = bucket.limit("a", 1).await;
+        assert!(matches!(result, Err(TokenBucketError::InvalidKey(_))));
+        assert!(!bucket.client.is_degraded());
+    }
+}
@@ -0,0 +1,95 @@
+//! Conversions between the limiter's retry-after seconds and the HTTP `Retry-After` header
+//!
+//! `Retry-After` is either a number of seconds (`120`) or an HTTP-date
+//! (`Wed, 21 Oct 2015 07:28:00 GMT`). Both forms can be produced and parsed.
+
+use std::{
+    cmp,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// The last second an HTTP-date can represent (`Fri, 31 Dec 9999 23:59:59 GMT`)
+const MAX_HTTP_DATE: u64 = 253_402_300_799;
+
+/// Format `retry_after` seconds as a delta-seconds `Retry-After` value
+pub fn retry_after_delta_seconds(retry_after: u64) -> String {
+    retry_after.to_string()
+}
+
+/// Format `retry_after` seconds from `now` (unix time) as an HTTP-date `Retry-After` value
+/// Dates past the year 9999 are clamped since HTTP-dates can't represent them
+pub fn retry_after_http_date(retry_after: u64, now: u64) -> String {
+    let at = cmp::min(now.saturating_add(retry_after), MAX_HTTP_DATE);
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(at))
+}
+
+/// Parse an upstream `Retry-After` value into seconds from `now` (unix time)
+/// Accepts both the delta-seconds and HTTP-date forms. Dates in the past yield `0`
+pub fn parse_retry_after(value: &str, now: u64) -> Option<u64> {
+    let value = value.trim();
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        // Saturate absurdly long delays instead of rejecting them
+        return Some(value.parse().unwrap_or(u64::MAX));
+    }
+    let at = httpdate::parse_http_date(value)
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(at.saturating_sub(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Wed, 21 Oct 2015 07:28:00 GMT`
+    const OCT_21_2015: u64 = 1_445_412_480;
+
+    #[test]
+    fn formats_delta_seconds() {
+        assert_eq!(retry_after_delta_seconds(0), "0");
+        assert_eq!(retry_after_delta_seconds(120), "120");
+    }
+
+    #[test]
+    fn formats_http_date() {
+        assert_eq!(
+            retry_after_http_date(120, OCT_21_2015 - 120),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        assert_eq!(
+            retry_after_http_date(u64::MAX, OCT_21_2015),
+            "Fri, 31 Dec 9999 23:59:59 GMT"
+        );
+    }
+
+    #[test]
+    fn parses_delta_seconds() {
+        assert_eq!(parse_retry_after("120", OCT_21_2015), Some(120));
+        assert_eq!(parse_retry_after(" 0 ", OCT_21_2015), Some(0));
+        assert_eq!(
+            parse_retry_after("99999999999999999999999", OCT_21_2015),
+            Some(u64::MAX)
+        );
+        assert_eq!(parse_retry_after("-1", OCT_21_2015), None);
+        assert_eq!(parse_retry_after("", OCT_21_2015), None);
+    }
+
+    #[test]
+    fn parses_http_date() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(parse_retry_after(date, OCT_21_2015 - 120), Some(120));
+        assert_eq!(parse_retry_after(date, OCT_21_2015 + 120), Some(0));
+        assert_eq!(parse_retry_after("tomorrow", OCT_21_2015), None);
+    }
+
+    #[test]
+    fn round_trips_both_forms() {
+        let now = OCT_21_2015;
+        let delta = retry_after_delta_seconds(3600);
+        assert_eq!(parse_retry_after(&delta, now), Some(3600));
+        let date = retry_after_http_date(3600, now);
+        assert_eq!(parse_retry_after(&date, now), Some(3600));
+    }
+}
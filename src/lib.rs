@@ -1,7 +1,13 @@
+#![allow(clippy::result_large_err)]
+
 use aws_sdk_dynamodb::{
-    error::SdkError,
-    operation::{put_item::PutItemError, query::QueryError},
-    types::{AttributeValue, Select},
+    config::AsyncSleep,
+    error::{BuildError, SdkError},
+    operation::{
+        batch_get_item::BatchGetItemError, get_item::GetItemError, put_item::PutItemError,
+        query::QueryError, update_item::UpdateItemError,
+    },
+    types::{AttributeValue, KeysAndAttributes, ReturnValuesOnConditionCheckFailure, Select},
     Client,
 };
 use aws_smithy_runtime_api::http::Response;
@@ -10,13 +16,61 @@ use serde::{Deserialize, Serialize};
 use serde_dynamo::{aws_sdk_dynamodb_1::to_item, from_item};
 use std::{
     cmp,
+    collections::{HashMap, HashSet},
+    future::{poll_fn, Future},
     num::NonZeroU64,
-    time::{SystemTime, UNIX_EPOCH},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
+mod buffered;
+mod cached;
+pub mod cidr;
+mod cost;
+#[cfg(feature = "axum")]
+pub mod extract;
+mod fallback;
+pub mod headers;
+mod memory;
+mod peek;
+mod refill;
+mod reservation;
+mod schema;
+mod sharding;
+#[cfg(test)]
+mod test_support;
+mod transaction;
+mod transient;
+
+pub use buffered::{BufferedClient, DEFAULT_FLUSH_INTERVAL};
+pub use cached::{CachedSettingsClient, DEFAULT_SETTINGS_TTL, SNAPSHOT_VERSION};
+pub use cost::{CostPolicy, Fixed, PerBytes};
+pub use fallback::{FallbackClient, DEFAULT_RECOVERY_INTERVAL};
+pub use memory::InMemoryClient;
+pub use peek::{CachedPeekClient, PeekResult, DEFAULT_PEEK_MAX_AGE};
+#[cfg(feature = "decay")]
+pub use refill::DecayPolicy;
+pub use refill::{elapsed_intervals, LinearRefill, RefillPolicy};
+pub use reservation::Reservation;
+pub use schema::SCHEMA_VERSION;
+pub use sharding::{ShardStrategy, Sharding};
+pub use transaction::{Transaction, TransactionMode, TransactionResult};
+pub use transient::TransientError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 /// The settings for a rate limit
+///
+/// Refill is exact integer arithmetic by default: whole tokens are added per whole `refill_interval`,
+/// and a bucket's `last_updated` only moves by whole intervals, so the time towards the next refill
+/// is carried over rather than rounded away. A fractional rate per second is expressed with a
+/// longer interval, e.g. 5 tokens every 2 seconds, see `for_rate`. To accrue tokens within an
+/// interval instead, use `TokenAccounting::FixedPoint`
 pub struct RateLimitSettings {
     /// The maximum number of tokens that can be stored
     pub max_tokens: u64,
@@ -28,6 +82,333 @@ pub struct RateLimitSettings {
     pub refill_interval: NonZeroU64,
 }
 
+impl RateLimitSettings {
+    /// Settings from plain integers, checked the same way as `rate_limit_settings!`
+    ///
+    /// ```
+    /// use distributed_ratelimit::{InvalidSettings, RateLimitSettings};
+    ///
+    /// let settings = RateLimitSettings::new(100, 100, 10, 60).unwrap();
+    /// assert_eq!(settings.refill_interval.get(), 60);
+    ///
+    /// let err = RateLimitSettings::new(100, 100, 10, 0).unwrap_err();
+    /// assert_eq!(err, InvalidSettings::ZeroRefillInterval);
+    /// ```
+    pub fn new(
+        max_tokens: u64,
+        starting_tokens: u64,
+        refill_rate: u64,
+        refill_interval: u64,
+    ) -> Result<Self, InvalidSettings> {
+        if starting_tokens > max_tokens {
+            return Err(InvalidSettings::StartingAboveMax);
+        }
+        let refill_interval =
+            NonZeroU64::new(refill_interval).ok_or(InvalidSettings::ZeroRefillInterval)?;
+        Ok(Self {
+            max_tokens,
+            starting_tokens,
+            refill_rate,
+            refill_interval,
+        })
+    }
+
+    #[doc(hidden)]
+    /// Used by `rate_limit_settings!`, panicking here fails the build when evaluated in a const
+    pub const fn checked_const(
+        max_tokens: u64,
+        starting_tokens: u64,
+        refill_rate: u64,
+        refill_interval: u64,
+    ) -> Self {
+        assert!(
+            starting_tokens <= max_tokens,
+            "starting_tokens must not exceed max_tokens"
+        );
+        let Some(refill_interval) = NonZeroU64::new(refill_interval) else {
+            panic!("refill_interval must not be zero");
+        };
+        Self {
+            max_tokens,
+            starting_tokens,
+            refill_rate,
+            refill_interval,
+        }
+    }
+
+    /// Split these settings across `nodes` that each limit on their own
+    /// Token counts are rounded up so small limits don't become zero, so the fleet
+    /// may allow up to `nodes - 1` more tokens than the original settings
+    pub fn per_node(&self, nodes: NonZeroU64) -> Self {
+        Self {
+            max_tokens: self.max_tokens.div_ceil(nodes.get()),
+            starting_tokens: self.starting_tokens.div_ceil(nodes.get()),
+            refill_rate: self.refill_rate.div_ceil(nodes.get()),
+            refill_interval: self.refill_interval,
+        }
+    }
+
+    /// Settings that refill `tokens_per_second` on average, starting full at `max_tokens`
+    ///
+    /// The rate is approximated by the integer `refill_rate` / `refill_interval` closest to it,
+    /// using an interval of at most `MAX_RATE_INTERVAL` seconds and the shortest interval among
+    /// equally close ones. With `RatePrecision::Strict` a rate that can't be matched within the
+    /// tolerance is an `InexactRate` error instead. Negative and NaN rates are never representable
+    ///
+    /// ```
+    /// use distributed_ratelimit::{RatePrecision, RateLimitSettings, TokenBucketError};
+    ///
+    /// let strict = RatePrecision::Strict { tolerance: 0.0 };
+    /// let settings = RateLimitSettings::for_rate(0.4, 10, strict).unwrap();
+    /// assert_eq!((settings.refill_rate, settings.refill_interval.get()), (2, 5));
+    /// assert_eq!(settings.tokens_per_second(), 0.4);
+    ///
+    /// // One token every 100,000 seconds needs a longer interval than is searched
+    /// let err = RateLimitSettings::for_rate(0.00001, 10, strict).unwrap_err();
+    /// assert!(matches!(err, TokenBucketError::InexactRate { .. }));
+    ///
+    /// // Lenient settings take the closest rate instead
+    /// let settings = RateLimitSettings::for_rate(0.00001, 10, RatePrecision::Lenient).unwrap();
+    /// assert_eq!((settings.refill_rate, settings.refill_interval.get()), (1, 86_400));
+    /// ```
+    pub fn for_rate(
+        tokens_per_second: f64,
+        max_tokens: u64,
+        precision: RatePrecision,
+    ) -> Result<Self, TokenBucketError> {
+        let mut best = (Rounding::Down.apply(tokens_per_second), ONE_SECOND);
+        let mut best_error = f64::INFINITY;
+        for interval in 1..=MAX_RATE_INTERVAL {
+            let refill_rate = (tokens_per_second * interval as f64).round() as u64;
+            let error = (refill_rate as f64 / interval as f64 - tokens_per_second).abs();
+            if error < best_error {
+                best = (refill_rate, NonZeroU64::new(interval).unwrap_or(ONE_SECOND));
+                best_error = error;
+            }
+            if error == 0.0 {
+                break;
+            }
+        }
+
+        let (refill_rate, refill_interval) = best;
+        let settings = Self {
+            max_tokens,
+            starting_tokens: max_tokens,
+            refill_rate,
+            refill_interval,
+        };
+        let effective = settings.tokens_per_second();
+        let representable = match precision {
+            RatePrecision::Lenient => tokens_per_second >= 0.0,
+            RatePrecision::Strict { tolerance } => {
+                tokens_per_second.is_finite()
+                    && (effective - tokens_per_second).abs() <= tolerance * tokens_per_second
+            }
+        };
+        if !representable {
+            return Err(TokenBucketError::InexactRate {
+                requested: tokens_per_second,
+                effective,
+            });
+        }
+        Ok(settings)
+    }
+
+    /// The average number of tokens added per second
+    pub fn tokens_per_second(&self) -> f64 {
+        self.refill_rate as f64 / self.refill_interval.get() as f64
+    }
+
+    /// The limit in the rate and burst terms APIs document, e.g. for generated docs
+    ///
+    /// ```
+    /// use distributed_ratelimit::RateLimitSettings;
+    /// use std::time::Duration;
+    ///
+    /// let settings = RateLimitSettings::new(150, 150, 100, 60).unwrap();
+    /// let description = settings.describe_limit();
+    /// assert_eq!((description.requests, description.burst), (100, 150));
+    /// assert_eq!(description.per, Duration::from_secs(60));
+    /// assert_eq!(description.to_string(), "100 requests per minute, burst 150");
+    ///
+    /// let settings = RateLimitSettings::new(1, 1, 1, 1).unwrap();
+    /// assert_eq!(settings.describe_limit().to_string(), "1 request per second, burst 1");
+    ///
+    /// let settings = RateLimitSettings::new(50, 10, 5, 7200).unwrap();
+    /// assert_eq!(settings.describe_limit().to_string(), "5 requests per 2 hours, burst 50");
+    ///
+    /// let settings = RateLimitSettings::new(10, 10, 3, 90).unwrap();
+    /// assert_eq!(settings.describe_limit().to_string(), "3 requests per 90 seconds, burst 10");
+    /// ```
+    pub fn describe_limit(&self) -> LimitDescription {
+        LimitDescription {
+            requests: self.refill_rate,
+            per: Duration::from_secs(self.refill_interval.get()),
+            burst: self.max_tokens,
+        }
+    }
+
+    /// Whether a bucket with these settings will ever have `cost` tokens, once emptied
+    ///
+    /// ```
+    /// use distributed_ratelimit::RateLimitSettings;
+    ///
+    /// let settings = RateLimitSettings::new(10, 10, 1, 60).unwrap();
+    /// assert!(settings.can_ever_allow(10));
+    /// // More than the bucket can hold
+    /// assert!(!settings.can_ever_allow(11));
+    /// // Nothing is ever refilled
+    /// let no_refill = RateLimitSettings::new(10, 10, 0, 60).unwrap();
+    /// assert!(!no_refill.can_ever_allow(1));
+    /// ```
+    pub fn can_ever_allow(&self, cost: u64) -> bool {
+        cost <= self.max_tokens && self.refill_rate > 0
+    }
+
+    /// Multiply `max_tokens` and `refill_rate` by `factor`, rounding the results with `rounding`
+    /// `refill_interval` is kept, and `starting_tokens` too unless it's over the new `max_tokens`
+    pub fn scaled(&self, factor: f64, rounding: Rounding) -> Self {
+        let max_tokens = rounding.apply(self.max_tokens as f64 * factor);
+        Self {
+            max_tokens,
+            starting_tokens: cmp::min(self.starting_tokens, max_tokens),
+            refill_rate: rounding.apply(self.refill_rate as f64 * factor),
+            refill_interval: self.refill_interval,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// A limit as `requests` per `per`, with up to `burst` at once, from `RateLimitSettings::describe_limit`
+/// Counts are in tokens, which are requests when every request costs one
+pub struct LimitDescription {
+    pub requests: u64,
+    pub per: Duration,
+    pub burst: u64,
+}
+
+impl std::fmt::Display for LimitDescription {
+    /// Formats as e.g. `100 requests per minute, burst 150`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let requests = if self.requests == 1 {
+            "request"
+        } else {
+            "requests"
+        };
+        write!(f, "{} {requests} per ", self.requests)?;
+        let secs = self.per.as_secs();
+        let (count, unit) = [(86_400, "day"), (3_600, "hour"), (60, "minute")]
+            .into_iter()
+            .find(|(unit_secs, _)| secs.is_multiple_of(*unit_secs))
+            .map(|(unit_secs, unit)| (secs / unit_secs, unit))
+            .unwrap_or((secs, "second"));
+        match count {
+            1 => write!(f, "{unit}")?,
+            _ => write!(f, "{count} {unit}s")?,
+        }
+        write!(f, ", burst {}", self.burst)
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Why `RateLimitSettings::new` rejected its arguments
+pub enum InvalidSettings {
+    #[error("refill_interval must not be zero")]
+    ZeroRefillInterval,
+    #[error("starting_tokens must not exceed max_tokens")]
+    StartingAboveMax,
+}
+
+/// The longest `refill_interval` `RateLimitSettings::for_rate` will pick, one day
+pub const MAX_RATE_INTERVAL: u64 = 86_400;
+
+const ONE_SECOND: NonZeroU64 = NonZeroU64::MIN;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+/// How closely `RateLimitSettings::for_rate` has to match the requested rate
+pub enum RatePrecision {
+    /// Use the closest representable rate, however far off
+    #[default]
+    Lenient,
+    /// Fail unless the effective rate is within `tolerance` of the requested rate,
+    /// as a fraction of it, e.g. `0.01` for 1%. `0.0` only accepts exact rates
+    Strict { tolerance: f64 },
+}
+
+/// Build `RateLimitSettings` from constants, checked at compile time
+///
+/// The build fails if `refill_interval` is zero or `starting_tokens` is more than `max_tokens`.
+/// Settings only known at runtime can still be built directly.
+///
+/// ```
+/// use distributed_ratelimit::{rate_limit_settings, RateLimitSettings};
+///
+/// const API_LIMIT: RateLimitSettings = rate_limit_settings! {
+///     max_tokens: 100,
+///     starting_tokens: 100,
+///     refill_rate: 10,
+///     refill_interval: 60,
+/// };
+/// assert_eq!(API_LIMIT.refill_interval.get(), 60);
+/// ```
+///
+/// ```compile_fail
+/// let settings = distributed_ratelimit::rate_limit_settings! {
+///     max_tokens: 100,
+///     starting_tokens: 100,
+///     refill_rate: 10,
+///     refill_interval: 0,
+/// };
+/// ```
+///
+/// ```compile_fail
+/// let settings = distributed_ratelimit::rate_limit_settings! {
+///     max_tokens: 10,
+///     starting_tokens: 100,
+///     refill_rate: 10,
+///     refill_interval: 60,
+/// };
+/// ```
+#[macro_export]
+macro_rules! rate_limit_settings {
+    (
+        max_tokens: $max_tokens:expr,
+        starting_tokens: $starting_tokens:expr,
+        refill_rate: $refill_rate:expr,
+        refill_interval: $refill_interval:expr $(,)?
+    ) => {{
+        const SETTINGS: $crate::RateLimitSettings = $crate::RateLimitSettings::checked_const(
+            $max_tokens,
+            $starting_tokens,
+            $refill_rate,
+            $refill_interval,
+        );
+        SETTINGS
+    }};
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+/// How fractional token counts are rounded when settings are scaled
+pub enum Rounding {
+    /// Round towards fewer tokens, the conservative choice
+    #[default]
+    Down,
+    /// Round towards more tokens, the generous choice
+    Up,
+}
+
+impl Rounding {
+    fn apply(self, value: f64) -> u64 {
+        // `as` saturates, so negative and NaN values become 0
+        match self {
+            Rounding::Down => value.floor() as u64,
+            Rounding::Up => value.ceil() as u64,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 /// A single item in the cache
 pub struct RateLimitItem {
@@ -35,6 +416,22 @@ pub struct RateLimitItem {
     pub last_updated: u64,
     /// The number of tokens remaining
     pub tokens: u64,
+    /// The `max_tokens` the bucket was last written under, only recorded when
+    /// `TokenBucket::quota_increase` is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap: Option<u64>,
+    /// Millionths of a token held on top of `tokens`, always less than `FRACTION_SCALE`
+    /// Only accrued under `TokenAccounting::FixedPoint`, where the bucket holds
+    /// `tokens * FRACTION_SCALE + fraction` and `tokens` is that floored to whole tokens
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fraction: u64,
+}
+
+/// The fixed point scale of `RateLimitItem::fraction`, a millionth of a token
+pub const FRACTION_SCALE: u64 = 1_000_000;
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
 }
 
 impl RateLimitItem {
@@ -42,6 +439,8 @@ impl RateLimitItem {
         Self {
             last_updated: current_unix_time(),
             tokens,
+            cap: None,
+            fraction: 0,
         }
     }
 }
@@ -49,8 +448,16 @@ impl RateLimitItem {
 /// Primary abstraction to decouple the cache from the rate limiter
 /// This allows for the cache to be in redis, dynamodb, etc
 /// Currently only dynamodb is supported
-pub trait TokenBucketClient {
+pub trait TokenBucketClient: Sync {
     type Error;
+    /// Get the limit and settings exactly as they are stored, `None` for rows that don't exist
+    fn get_raw(
+        &self,
+        id: &str,
+    ) -> impl std::future::Future<
+        Output = Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error>,
+    > + Send;
+
     /// Get the current limit and settings from the cache
     /// If the limit or settings are not in the cache, the default settings will be used
     /// If the limit is not in the cache, a new limit will be created with the starting tokens
@@ -58,22 +465,152 @@ pub trait TokenBucketClient {
         &self,
         id: &str,
         default_settings: RateLimitSettings,
-    ) -> impl std::future::Future<Output = Result<(RateLimitItem, RateLimitSettings), Self::Error>> + Send;
+    ) -> impl std::future::Future<Output = Result<(RateLimitItem, RateLimitSettings), Self::Error>> + Send
+    {
+        async move {
+            let (limit, settings) = self.get_raw(id).await?;
+            let settings = settings.unwrap_or(default_settings);
+            let limit = limit.unwrap_or_else(|| RateLimitItem::new(settings.starting_tokens));
+            Ok((limit, settings))
+        }
+    }
 
     /// Put a new limit into the cache
+    /// The write is dropped if a limit with a newer `last_updated` is already stored
     fn put_limit(
         &self,
         id: &str,
         limit: RateLimitItem,
-    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
+    ) -> impl std::future::Future<Output = Result<PutOutcome, Self::Error>> + Send;
     /// Put a new settings into the cache
     fn put_settings(
         &self,
         id: &str,
         settings: RateLimitSettings,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Subtract up to `tokens` from the stored limit, doing nothing if the id has no stored limit
+    /// Like `refund`, implementations should subtract atomically. The default implementation
+    /// reads and writes the limit
+    fn spend(
+        &self,
+        id: &str,
+        tokens: u64,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        async move {
+            let (limit, _) = self.get_raw(id).await?;
+            if let Some(mut limit) = limit {
+                limit.tokens = limit.tokens.saturating_sub(tokens);
+                self.put_limit(id, limit).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Put `new` settings only if the stored settings are exactly `expected`, `None` meaning
+    /// the id has no settings yet. Returns whether the settings were written
+    /// Implementations should compare and write atomically. The default implementation reads
+    /// and then writes, so a concurrent write can land in between
+    fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> impl std::future::Future<Output = Result<bool, Self::Error>> + Send {
+        async move {
+            if self.get_settings(id).await? != expected {
+                return Ok(false);
+            }
+            self.put_settings(id, new).await?;
+            Ok(true)
+        }
+    }
+
+    /// Add `tokens` to the stored limit, doing nothing if the id has no stored limit
+    /// Implementations should add atomically so concurrent refunds all count. The default
+    /// implementation reads and writes the limit, so concurrent refunds can overwrite each other
+    fn refund(
+        &self,
+        id: &str,
+        tokens: u64,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        async move {
+            let (limit, _) = self.get_raw(id).await?;
+            if let Some(mut limit) = limit {
+                limit.tokens = limit.tokens.saturating_add(tokens);
+                self.put_limit(id, limit).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Get the named sub-buckets stored under an id, as they are stored
+    /// Sub-buckets share the id's settings. The default implementation has none
+    fn get_sub_buckets(
+        &self,
+        _id: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<(String, RateLimitItem)>, Self::Error>> + Send
+    {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Get the settings stored for an id, `None` if the id has no settings of its own
+    fn get_settings(
+        &self,
+        id: &str,
+    ) -> impl std::future::Future<Output = Result<Option<RateLimitSettings>, Self::Error>> + Send;
+
+    /// Get the settings stored for many ids, in the same order as `ids`
+    /// The default implementation calls `get_settings` for each id in turn
+    fn get_settings_many(
+        &self,
+        ids: &[&str],
+    ) -> impl std::future::Future<Output = Result<Vec<Option<RateLimitSettings>>, Self::Error>> + Send
+    {
+        async move {
+            let mut settings = Vec::with_capacity(ids.len());
+            for id in ids {
+                settings.push(self.get_settings(id).await?);
+            }
+            Ok(settings)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// What happened to a `put_limit` write
+pub enum PutOutcome {
+    /// The limit was stored
+    Written,
+    /// A newer limit was already stored so the write was dropped
+    Conflict {
+        /// The limit that's stored, if the backend returned it
+        current: Option<RateLimitItem>,
+    },
 }
 
+/// The attributes of a LIMIT row
+const LIMIT_ATTRIBUTES: [&str; 5] = [
+    "tokens",
+    "last_updated",
+    "cap",
+    "fraction",
+    schema::SCHEMA_VERSION_ATTRIBUTE,
+];
+
+/// The attribute added to rows copied to a `DeadLetter`
+const DEAD_LETTERED_AT: &str = "dead_lettered_at";
+
+/// The sort key prefix of sub-bucket rows, followed by the sub-bucket's name
+const SUB_BUCKET_PREFIX: &str = "LIMIT#";
+
+/// The most keys DynamoDB accepts in a single `BatchGetItem` request
+const BATCH_GET_LIMIT: usize = 100;
+/// The default for `TokenDynamoClient::batch_get_retries`
+pub const DEFAULT_BATCH_GET_RETRIES: u32 = 5;
+/// The first delay before retrying unprocessed keys, doubled on every retry
+const BATCH_GET_BASE_DELAY: Duration = Duration::from_millis(50);
+
 #[derive(Debug, Clone)]
 /// DynamoDB client for the token bucket
 /// The table must have a primary key with the name `pk_name` and a sort key with the name `sk_name`
@@ -86,80 +623,519 @@ pub struct TokenDynamoClient {
     pub pk_prefix: Option<String>,
     /// The name of the sort key
     pub sk_name: String,
+    /// How many times a batch read retries keys DynamoDB left unprocessed before giving up
+    pub batch_get_retries: u32,
+    /// What to do with a LIMIT or SETTINGS row that can't be deserialized
+    pub corrupt_item_policy: CorruptItemPolicy,
+    /// Where to copy corrupt rows before they are reset, so they can be inspected later
+    pub dead_letter: Option<DeadLetter>,
+    /// The attribute names the SETTINGS row is stored under
+    pub settings_attributes: SettingsAttributes,
+    /// Have DynamoDB return the stored limit when `put_limit` loses the conditional write,
+    /// so `PutOutcome::Conflict` carries it without another read
+    pub return_conflicting_item: bool,
+    /// Only read the attributes `get_raw` decodes, instead of whole rows
+    /// This shrinks the response for rows carrying other data, but DynamoDB still charges read
+    /// capacity for the full item size. Off by default, since rows copied to a `dead_letter`
+    /// would then only hold the projected attributes
+    pub project_attributes: bool,
+    /// See `with_backend_timeout`
+    backend_timeout: Option<Duration>,
     pub client: Client,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The attribute names each `RateLimitSettings` field is stored under
+/// Lets the SETTINGS row coexist with other data in the same table. Defaults to the field names
+pub struct SettingsAttributes {
+    pub max_tokens: String,
+    pub starting_tokens: String,
+    pub refill_rate: String,
+    pub refill_interval: String,
+}
+
+impl Default for SettingsAttributes {
+    fn default() -> Self {
+        Self::prefixed("")
+    }
+}
+
+impl SettingsAttributes {
+    /// The field names with `prefix` in front, e.g. `rl_max_tokens`
+    pub fn prefixed(prefix: &str) -> Self {
+        Self {
+            max_tokens: format!("{prefix}max_tokens"),
+            starting_tokens: format!("{prefix}starting_tokens"),
+            refill_rate: format!("{prefix}refill_rate"),
+            refill_interval: format!("{prefix}refill_interval"),
+        }
+    }
+
+    /// Each field name paired with the attribute it's stored under
+    fn names(&self) -> [(&'static str, &str); 4] {
+        [
+            ("max_tokens", &self.max_tokens),
+            ("starting_tokens", &self.starting_tokens),
+            ("refill_rate", &self.refill_rate),
+            ("refill_interval", &self.refill_interval),
+        ]
+    }
+
+    /// Rename serialized fields to their stored attribute names
+    fn to_stored(&self, item: HashMap<String, AttributeValue>) -> HashMap<String, AttributeValue> {
+        Self::rename(
+            item,
+            self.names().map(|(field, attribute)| (field, attribute)),
+        )
+    }
+
+    /// Rename stored attributes back to the field names serde expects
+    fn to_fields(&self, item: HashMap<String, AttributeValue>) -> HashMap<String, AttributeValue> {
+        Self::rename(
+            item,
+            self.names().map(|(field, attribute)| (attribute, field)),
+        )
+    }
+
+    fn rename(
+        mut item: HashMap<String, AttributeValue>,
+        renames: [(&str, &str); 4],
+    ) -> HashMap<String, AttributeValue> {
+        // Take every value out before inserting any, so names that swap don't clobber each other
+        let values = renames.map(|(from, to)| (item.remove(from), to));
+        for (value, to) in values {
+            if let Some(value) = value {
+                item.insert(to.into(), value);
+            }
+        }
+        item
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+/// How `TokenDynamoClient` handles rows it can't deserialize
+pub enum CorruptItemPolicy {
+    /// Treat the row as missing, so it's replaced by the next write
+    #[default]
+    Reset,
+    /// Fail the read with `TokenBucketError::SerdeError`
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Where `TokenDynamoClient` copies corrupt rows when `CorruptItemPolicy::Reset` is used
+/// The copy is the row as stored plus a `dead_lettered_at` unix time attribute.
+/// Only the first copy for a row is kept, so a row that's read many times before it's reset,
+/// or corrupted again later, doesn't overwrite the evidence already there
+pub enum DeadLetter {
+    /// The same table and partition, with this suffix added to the sort key, e.g. `#CORRUPT`
+    SortKeySuffix(String),
+    /// Another table with the same key schema, the row keeps its key
+    Table(String),
+}
+
 impl TokenDynamoClient {
-    fn format_pk(&self, id: &str) -> AttributeValue {
+    /// A client for `table_name` with a primary key named `pk` and a sort key named `sk`
+    pub fn new(client: Client, table_name: impl Into<String>) -> Self {
+        Self {
+            table_name: table_name.into(),
+            pk_name: "pk".into(),
+            pk_prefix: None,
+            sk_name: "sk".into(),
+            batch_get_retries: DEFAULT_BATCH_GET_RETRIES,
+            corrupt_item_policy: CorruptItemPolicy::Reset,
+            dead_letter: None,
+            settings_attributes: SettingsAttributes::default(),
+            return_conflicting_item: false,
+            project_attributes: false,
+            backend_timeout: None,
+            client,
+        }
+    }
+
+    /// Fail any single DynamoDB call, including the SDK's own retries, that takes longer than
+    /// `timeout` with `TokenBucketError::Timeout`
+    /// Calls are timed with the SDK client's `sleep_impl`, so this fails with
+    /// `TokenBucketError::NoSleepImpl` for a client without one, e.g. one built with
+    /// `Config::builder` and no `sleep_impl`. Clients configured by `aws_config::load_defaults` have one
+    pub fn with_backend_timeout(mut self, timeout: Duration) -> Result<Self, TokenBucketError> {
+        if self.client.config().sleep_impl().is_none() {
+            return Err(TokenBucketError::NoSleepImpl);
+        }
+        self.backend_timeout = Some(timeout);
+        Ok(self)
+    }
+
+    fn pk_value(&self, id: &str) -> String {
         match &self.pk_prefix {
-            Some(prefix) => AttributeValue::S(format!("{prefix}{id}")),
-            None => AttributeValue::S(id.into()),
+            Some(prefix) => format!("{prefix}{id}"),
+            None => id.into(),
+        }
+    }
+
+    fn format_pk(&self, id: &str) -> AttributeValue {
+        AttributeValue::S(self.pk_value(id))
+    }
+
+    fn settings_key(&self, id: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (self.pk_name.clone(), self.format_pk(id)),
+            (self.sk_name.clone(), AttributeValue::S("SETTINGS".into())),
+        ])
+    }
+
+    /// The SETTINGS row for `id`, checked so it can only ever be written to the SETTINGS sort key
+    fn settings_item(
+        &self,
+        id: &str,
+        settings: RateLimitSettings,
+    ) -> Result<HashMap<String, AttributeValue>, TokenBucketError> {
+        if self.pk_name == self.sk_name {
+            return Err(TokenBucketError::InvalidSettingsAttributes(format!(
+                "the partition and sort key are both named {}",
+                self.pk_name
+            )));
+        }
+        let mut reserved = [self.pk_name.as_str(), &self.sk_name]
+            .into_iter()
+            .chain(LIMIT_ATTRIBUTES);
+        if let Some(attribute) = reserved.find(|reserved| {
+            self.settings_attributes
+                .names()
+                .iter()
+                .any(|(_, attribute)| attribute == reserved)
+        }) {
+            return Err(TokenBucketError::InvalidSettingsAttributes(format!(
+                "{attribute} is a key or limit attribute"
+            )));
+        }
+
+        let mut item = self.settings_attributes.to_stored(to_item(settings)?);
+        item.extend(self.settings_key(id));
+        schema::stamp(&mut item);
+        Ok(item)
+    }
+
+    /// Deserialize a LIMIT row, applying the corrupt item policy if it can't be
+    async fn decode_limit(
+        &self,
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<Option<RateLimitItem>, TokenBucketError> {
+        schema::check_supported(&item)?;
+        let decoded = schema::migrate_limit(item.clone()).and_then(from_item);
+        self.check_decoded(decoded, item).await
+    }
+
+    /// Deserialize a SETTINGS row stored under `settings_attributes`
+    async fn decode_settings(
+        &self,
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<Option<RateLimitSettings>, TokenBucketError> {
+        schema::check_supported(&item)?;
+        let decoded = schema::migrate_settings(self.settings_attributes.to_fields(item.clone()))
+            .and_then(from_item);
+        self.check_decoded(decoded, item).await
+    }
+
+    /// Apply the corrupt item policy to a failed decode of the stored `item`
+    async fn check_decoded<T>(
+        &self,
+        decoded: Result<T, serde_dynamo::Error>,
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<Option<T>, TokenBucketError> {
+        let error = match decoded {
+            Ok(value) => return Ok(Some(value)),
+            Err(error) => error,
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%error, "corrupt rate limit item");
+
+        match self.corrupt_item_policy {
+            CorruptItemPolicy::Error => Err(error.into()),
+            CorruptItemPolicy::Reset => {
+                if let Some(dead_letter) = &self.dead_letter {
+                    // Keep the evidence before the row is overwritten, failing the read if we can't
+                    self.put_dead_letter(dead_letter, item).await?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    async fn put_dead_letter(
+        &self,
+        dead_letter: &DeadLetter,
+        mut item: HashMap<String, AttributeValue>,
+    ) -> Result<(), TokenBucketError> {
+        item.insert(
+            DEAD_LETTERED_AT.into(),
+            AttributeValue::N(current_unix_time().to_string()),
+        );
+        let table_name = match dead_letter {
+            DeadLetter::SortKeySuffix(suffix) => {
+                if let Some(AttributeValue::S(sk)) = item.get_mut(&self.sk_name) {
+                    sk.push_str(suffix);
+                }
+                &self.table_name
+            }
+            DeadLetter::Table(table_name) => table_name,
+        };
+
+        let put = self
+            .client
+            .put_item()
+            .table_name(table_name)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(#key)")
+            .expression_attribute_names("#key", &self.pk_name);
+        match self.bounded(put.send()).await? {
+            Ok(_) => Ok(()),
+            // Already copied by an earlier read
+            Err(SdkError::ServiceError(s))
+                if matches!(s.err(), PutItemError::ConditionalCheckFailedException(_)) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(TokenBucketError::DynamoPut(e)),
+        }
+    }
+
+    /// The projection expression for the attributes `get_raw` decodes, with the attribute name
+    /// each of its placeholders stands for. `#key` is the partition key, as in the query
+    fn projection(&self) -> (String, Vec<(String, &str)>) {
+        let attributes = [self.sk_name.as_str()]
+            .into_iter()
+            .chain(LIMIT_ATTRIBUTES)
+            .chain(
+                self.settings_attributes
+                    .names()
+                    .map(|(_, attribute)| attribute),
+            );
+        let mut names = vec![("#key".to_string(), self.pk_name.as_str())];
+        names.extend(
+            attributes
+                .enumerate()
+                .map(|(i, attribute)| (format!("#a{i}"), attribute)),
+        );
+        let projection = names
+            .iter()
+            .map(|(placeholder, _)| placeholder.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        (projection, names)
+    }
+
+    /// Apply `update` to the LIMIT row of `id` if `condition` holds, returning whether it did
+    /// The condition also has to stop ADD from creating a limit with no last_updated
+    async fn update_limit<const N: usize>(
+        &self,
+        id: &str,
+        update: &str,
+        condition: &str,
+        numbers: [(&str, String); N],
+    ) -> Result<bool, TokenBucketError> {
+        let mut request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(&self.pk_name, self.format_pk(id))
+            .key(&self.sk_name, AttributeValue::S("LIMIT".into()))
+            .update_expression(update)
+            .condition_expression(condition);
+        for (name, number) in numbers {
+            request = request.expression_attribute_values(name, AttributeValue::N(number));
+        }
+
+        match self.bounded(request.send()).await? {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(s)) => match s.err() {
+                UpdateItemError::ConditionalCheckFailedException(_) => Ok(false),
+                _ => Err(TokenBucketError::DynamoUpdate(SdkError::ServiceError(s))),
+            },
+            Err(e) => Err(TokenBucketError::DynamoUpdate(e)),
+        }
+    }
+
+    /// Run a DynamoDB call, failing with `Timeout` if it outlasts `backend_timeout`
+    /// The call's own result is returned as is, so callers can still match on its error
+    async fn bounded<O>(&self, call: impl Future<Output = O>) -> Result<O, TokenBucketError> {
+        let Some(timeout) = self.backend_timeout else {
+            return Ok(call.await);
+        };
+        // Checked by `with_backend_timeout`, but `client` can have been replaced since
+        let Some(sleep) = self.client.config().sleep_impl() else {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                ?timeout,
+                "the DynamoDB client has no sleep_impl, backend_timeout isn't applied"
+            );
+            return Ok(call.await);
+        };
+        let mut call = pin!(call);
+        let mut expired = pin!(sleep.sleep(timeout));
+        poll_fn(|cx| {
+            if let Poll::Ready(output) = call.as_mut().poll(cx) {
+                return Poll::Ready(Ok(output));
+            }
+            expired
+                .as_mut()
+                .poll(cx)
+                .map(|()| Err(TokenBucketError::Timeout(timeout)))
+        })
+        .await
+    }
+
+    /// Wait before retrying unprocessed keys, using the sleep configured on the SDK client
+    /// Retries immediately if the client has no sleep implementation
+    async fn batch_get_backoff(&self, attempt: u32) {
+        if let Some(sleep) = self.client.config().sleep_impl() {
+            let delay = BATCH_GET_BASE_DELAY.saturating_mul(1 << attempt.min(8));
+            sleep.sleep(delay).await;
+        }
+    }
+}
+
+/// The rows of an id's partition that `get_raw` reads, sorted by their sort key
+struct BucketRows {
+    limit: Option<HashMap<String, AttributeValue>>,
+    settings: Option<HashMap<String, AttributeValue>>,
+}
+
+impl BucketRows {
+    /// Sort `items` into the LIMIT and SETTINGS rows, failing with `DuplicateRow` if either
+    /// appears twice. Anything else in the partition, like sub-buckets or dead letters, is skipped
+    fn from_items(
+        items: Vec<HashMap<String, AttributeValue>>,
+        sk_name: &str,
+    ) -> Result<Self, TokenBucketError> {
+        let mut rows = Self {
+            limit: None,
+            settings: None,
+        };
+        for item in items {
+            let (row, sk) = match item.get(sk_name) {
+                Some(AttributeValue::S(sk)) if sk == "LIMIT" => (&mut rows.limit, "LIMIT"),
+                Some(AttributeValue::S(sk)) if sk == "SETTINGS" => (&mut rows.settings, "SETTINGS"),
+                _ => continue,
+            };
+            if row.is_some() {
+                return Err(TokenBucketError::DuplicateRow(sk.into()));
+            }
+            *row = Some(item);
         }
+        Ok(rows)
     }
 }
 
 impl TokenBucketClient for TokenDynamoClient {
     type Error = TokenBucketError;
-    async fn get(
+    async fn get_raw(
         &self,
         id: &str,
-        default_settings: RateLimitSettings,
-    ) -> Result<(RateLimitItem, RateLimitSettings), Self::Error> {
-        let items = self
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        let mut query = self
             .client
             .query()
+            .table_name(&self.table_name)
             .key_condition_expression("#key = :value")
             .expression_attribute_names("#key", &self.pk_name)
-            .expression_attribute_values(":value", self.format_pk(id))
-            .select(Select::AllAttributes)
-            .send()
-            .await?
-            .items
-            .unwrap_or_default();
-
-        let mut limit: Option<RateLimitItem> = None;
-        let mut settings: Option<RateLimitSettings> = None;
-        for item in items {
-            match (item.get(&self.sk_name), &limit, &settings) {
-                (Some(AttributeValue::S(value)), None, _) if { value == "LIMIT" } => {
-                    limit = from_item(item).ok()
-                }
-                (Some(AttributeValue::S(value)), _, None) if { value == "SETTINGS" } => {
-                    settings = from_item(item).ok()
-                }
-                (Some(_), Some(_), Some(_)) => break,
-                _ => continue,
+            .expression_attribute_values(":value", self.format_pk(id));
+        if self.project_attributes {
+            let (projection, names) = self.projection();
+            for (placeholder, attribute) in names {
+                query = query.expression_attribute_names(placeholder, attribute);
             }
+            query = query.projection_expression(projection);
+        } else {
+            query = query.select(Select::AllAttributes);
         }
-        let settings = settings.unwrap_or(default_settings);
-        let limit = limit.unwrap_or_else(|| RateLimitItem::new(settings.starting_tokens));
+        let items = self.bounded(query.send()).await??.items.unwrap_or_default();
 
+        let rows = BucketRows::from_items(items, &self.sk_name)?;
+        let limit = match rows.limit {
+            Some(item) => self.decode_limit(item).await?,
+            None => None,
+        };
+        let settings = match rows.settings {
+            Some(item) => self.decode_settings(item).await?,
+            None => None,
+        };
         Ok((limit, settings))
     }
 
-    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<(), Self::Error> {
+    /// Sub-buckets are the rows in the id's partition with a `LIMIT#<name>` sort key
+    async fn get_sub_buckets(&self, id: &str) -> Result<Vec<(String, RateLimitItem)>, Self::Error> {
+        let query = self
+            .client
+            .query()
+            .table_name(&self.table_name)
+            .key_condition_expression("#key = :value AND begins_with(#sort, :prefix)")
+            .expression_attribute_names("#key", &self.pk_name)
+            .expression_attribute_names("#sort", &self.sk_name)
+            .expression_attribute_values(":value", self.format_pk(id))
+            .expression_attribute_values(":prefix", AttributeValue::S(SUB_BUCKET_PREFIX.into()));
+        let items = self.bounded(query.send()).await??.items.unwrap_or_default();
+
+        let mut sub_buckets = Vec::with_capacity(items.len());
+        for item in items {
+            let name = match item.get(&self.sk_name) {
+                Some(AttributeValue::S(sk)) => sk.strip_prefix(SUB_BUCKET_PREFIX).map(String::from),
+                _ => None,
+            };
+            let Some(name) = name else { continue };
+            if item.contains_key(DEAD_LETTERED_AT) {
+                // A dead letter copy of the LIMIT row, e.g. under `LIMIT#CORRUPT`
+                continue;
+            }
+            if let Some(limit) = self.decode_limit(item).await? {
+                sub_buckets.push((name, limit));
+            }
+        }
+        Ok(sub_buckets)
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
         let last_updated = limit.last_updated.to_string();
-        let item = to_item(limit)?;
+        let mut item = to_item(limit)?;
+        schema::stamp(&mut item);
 
-        let result = self
+        let put = self
             .client
             .put_item()
             .table_name(&self.table_name)
             .set_item(Some(item))
             .item(&self.pk_name, self.format_pk(id))
             .item(&self.sk_name, AttributeValue::S("LIMIT".into()))
-            .condition_expression("last_updated <= :new_updated")
+            // A missing or non numeric last_updated means there's no limit yet or it's corrupt,
+            // either way it should be replaced
+            .condition_expression(
+                "attribute_not_exists(last_updated) OR NOT attribute_type(last_updated, :number) OR last_updated <= :new_updated",
+            )
             .expression_attribute_values(":new_updated", AttributeValue::N(last_updated))
-            .send()
-            .await;
+            .expression_attribute_values(":number", AttributeValue::S("N".into()))
+            .set_return_values_on_condition_check_failure(
+                self.return_conflicting_item
+                    .then_some(ReturnValuesOnConditionCheckFailure::AllOld),
+            );
 
-        match result {
-            Ok(_) => Ok(()),
+        match self.bounded(put.send()).await? {
+            Ok(_) => Ok(PutOutcome::Written),
             Err(SdkError::ServiceError(s)) => match s.err() {
                 // This can fail if the limit was updated by another request
                 // This is fine, we just want to make sure we don't overwrite a newer limit
                 // Something something eventually consistent
-                PutItemError::ConditionalCheckFailedException(_) => Ok(()),
+                PutItemError::ConditionalCheckFailedException(e) => {
+                    let current = match e.item() {
+                        Some(item) => {
+                            // A row from newer code fails the write as it would fail a read
+                            schema::check_supported(item)?;
+                            schema::migrate_limit(item.clone()).and_then(from_item).ok()
+                        }
+                        None => None,
+                    };
+                    Ok(PutOutcome::Conflict { current })
+                }
                 _ => Err(TokenBucketError::DynamoPut(SdkError::ServiceError(s))),
             },
             Err(e) => Err(TokenBucketError::DynamoPut(e)),
@@ -167,63 +1143,1115 @@ impl TokenBucketClient for TokenDynamoClient {
     }
 
     async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
-        let item = to_item(settings)?;
-        self.client
+        let item = self.settings_item(id, settings)?;
+        let put = self
+            .client
             .put_item()
             .table_name(&self.table_name)
             .set_item(Some(item))
-            .item(&self.pk_name, self.format_pk(id))
-            .item(&self.sk_name, AttributeValue::S("SETTINGS".into()))
-            .send()
-            .await?;
+            // SETTINGS never holds limit attributes, so if this row does the keys are misconfigured
+            .condition_expression(
+                "attribute_not_exists(last_updated) AND attribute_not_exists(tokens)",
+            );
+        self.bounded(put.send()).await??;
 
         Ok(())
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
-pub enum LimitResult {
-    Allow { remaining: u64 },
-    Deny,
-}
+    async fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> Result<bool, Self::Error> {
+        let item = self.settings_item(id, new)?;
+        let mut put = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(item));
+        match expected {
+            Some(expected) => {
+                let mut expected = self.settings_attributes.to_stored(to_item(expected)?);
+                let mut conditions = Vec::with_capacity(4);
+                for (i, (field, attribute)) in
+                    self.settings_attributes.names().into_iter().enumerate()
+                {
+                    let Some(value) = expected.remove(attribute) else {
+                        continue;
+                    };
+                    conditions.push(match field {
+                        // A v1 row has no starting_tokens and starts at max_tokens, which is `#a0`
+                        "starting_tokens" => format!(
+                            "(#a{i} = :a{i} OR (attribute_not_exists(#a{i}) AND #a0 = :a{i}))"
+                        ),
+                        _ => format!("#a{i} = :a{i}"),
+                    });
+                    put = put
+                        .expression_attribute_names(format!("#a{i}"), attribute)
+                        .expression_attribute_values(format!(":a{i}"), value);
+                }
+                put = put.condition_expression(conditions.join(" AND "));
+            }
+            None => {
+                put = put
+                    .condition_expression("attribute_not_exists(#key)")
+                    .expression_attribute_names("#key", &self.pk_name);
+            }
+        }
 
-pub struct TokenBucket<T: TokenBucketClient> {
-    client: T,
-    pub default_settings: RateLimitSettings,
-}
+        match self.bounded(put.send()).await? {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(s))
+                if matches!(s.err(), PutItemError::ConditionalCheckFailedException(_)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(TokenBucketError::DynamoPut(e)),
+        }
+    }
 
-impl<T: TokenBucketClient> TokenBucket<T> {
-    pub fn new(client: T, default_settings: RateLimitSettings) -> Result<Self, TokenBucketError> {
-        Ok(Self {
-            client,
-            default_settings,
-        })
+    async fn refund(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        // ADD is atomic, so concurrent refunds accumulate instead of racing on the write.
+        // A missing limit means nothing was spent, so there's nothing to give back
+        self.update_limit(
+            id,
+            "ADD tokens :tokens",
+            "attribute_exists(tokens)",
+            [(":tokens", tokens.to_string())],
+        )
+        .await?;
+        Ok(())
     }
 
+    async fn spend(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        let delta = format!("-{tokens}");
+        let spent = self
+            .update_limit(
+                id,
+                "ADD tokens :delta",
+                "tokens >= :tokens",
+                [(":delta", delta), (":tokens", tokens.to_string())],
+            )
+            .await?;
+        if !spent {
+            // Not enough left to subtract from without going negative, so empty it instead
+            self.update_limit(
+                id,
+                "SET tokens = :zero",
+                "tokens < :tokens",
+                [(":zero", "0".into()), (":tokens", tokens.to_string())],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        let get = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.settings_key(id)));
+        let item = self.bounded(get.send()).await??.item;
+
+        match item {
+            Some(item) => self.decode_settings(item).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn get_settings_many(
+        &self,
+        ids: &[&str],
+    ) -> Result<Vec<Option<RateLimitSettings>>, Self::Error> {
+        // BatchGetItem rejects duplicate keys in a request
+        let mut seen = HashSet::new();
+        let keys: Vec<_> = ids
+            .iter()
+            .filter(|id| seen.insert(**id))
+            .map(|id| self.settings_key(id))
+            .collect();
+
+        let mut found: HashMap<String, RateLimitSettings> = HashMap::new();
+        for chunk in keys.chunks(BATCH_GET_LIMIT) {
+            let mut pending = chunk.to_vec();
+            let mut attempt = 0;
+            // Keys DynamoDB doesn't get to (usually from throttling) must not be mistaken for
+            // keys without settings, so retry them and fail if they still can't be read
+            while !pending.is_empty() {
+                if attempt > 0 {
+                    if attempt > self.batch_get_retries {
+                        let keys = pending
+                            .iter()
+                            .filter_map(|key| match key.get(&self.pk_name) {
+                                Some(AttributeValue::S(pk)) => Some(pk.clone()),
+                                _ => None,
+                            })
+                            .collect();
+                        return Err(TokenBucketError::UnprocessedKeys(keys));
+                    }
+                    self.batch_get_backoff(attempt - 1).await;
+                }
+                attempt += 1;
+
+                let request = KeysAndAttributes::builder()
+                    .set_keys(Some(pending))
+                    .build()?;
+                let get = self
+                    .client
+                    .batch_get_item()
+                    .request_items(&self.table_name, request);
+                let output = self.bounded(get.send()).await??;
+
+                let items = output
+                    .responses
+                    .and_then(|mut responses| responses.remove(&self.table_name))
+                    .unwrap_or_default();
+                for item in items {
+                    let Some(AttributeValue::S(pk)) = item.get(&self.pk_name) else {
+                        continue;
+                    };
+                    let pk = pk.clone();
+                    if let Some(settings) = self.decode_settings(item).await? {
+                        found.insert(pk, settings);
+                    }
+                }
+
+                pending = output
+                    .unprocessed_keys
+                    .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+                    .map(|unprocessed| unprocessed.keys)
+                    .unwrap_or_default();
+            }
+        }
+
+        Ok(ids
+            .iter()
+            .map(|id| found.get(&self.pk_value(id)).copied())
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum LimitResult {
+    Allow {
+        remaining: u64,
+    },
+    Deny {
+        /// Seconds until enough tokens will have refilled for the same cost to be allowed
+        /// `u64::MAX` if the bucket can never hold enough tokens
+        retry_after: u64,
+        /// The unix time at which the same request would be allowed, `now + retry_after`
+        allowed_at: u64,
+        /// Whether the same request will ever be allowed under the current settings
+        /// `false` when the cost is over `max_tokens` or nothing is refilled, so retrying is pointless
+        recoverable: bool,
+    },
+}
+
+impl LimitResult {
+    /// The number of seconds to wait before retrying, if the request was denied
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            LimitResult::Allow { .. } => None,
+            LimitResult::Deny { retry_after, .. } => Some(*retry_after),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The results of `TokenBucket::limit_many`, `results[i]` is the result for `ids[i]`
+pub struct BatchLimitResult {
+    pub ids: Vec<String>,
+    pub results: Vec<LimitResult>,
+}
+
+impl BatchLimitResult {
+    /// Each id paired with its result, in input order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &LimitResult)> {
+        self.ids.iter().map(String::as_str).zip(&self.results)
+    }
+
+    /// The ids that were denied, in input order
+    pub fn denied(&self) -> impl Iterator<Item = &str> {
+        self.iter()
+            .filter(|(_, result)| matches!(result, LimitResult::Deny { .. }))
+            .map(|(id, _)| id)
+    }
+}
+
+pub struct TokenBucket<T: TokenBucketClient, R: RefillPolicy = LinearRefill> {
+    client: T,
+    refill_policy: R,
+    pub default_settings: RateLimitSettings,
+    /// If set, a bucket whose `last_updated` is more than this many seconds old is
+    /// logged as stale before it's refilled.
+    /// This usually points at a corrupt or test timestamp. Only available with the `tracing` feature
+    #[cfg(feature = "tracing")]
+    pub stale_threshold_secs: Option<u64>,
+    /// The longest id in bytes `limit` will accept
+    /// Defaults to `DEFAULT_MAX_KEY_LENGTH`, which leaves room for a prefix under DynamoDB's 2048 byte key limit
+    pub max_key_length: usize,
+    /// Scales every key's `max_tokens` and `refill_rate`, e.g. `0.5` to halve all limits during an incident
+    pub multiplier: Option<f64>,
+    /// How the scaled token counts are rounded, `Rounding::Down` by default
+    pub multiplier_rounding: Rounding,
+    /// How many tokens a key's first bucket starts with, `SeedPolicy::StartingTokens` by default
+    pub seed_policy: SeedPolicy,
+    /// What to do for a bucket whose `max_tokens` has gone up since it was last written,
+    /// `QuotaIncrease::Ignore` by default
+    pub quota_increase: QuotaIncrease,
+    /// What to do for a bucket holding more than `max_tokens`, `OverMaxPolicy::ClampImmediately`
+    /// by default
+    pub over_max: OverMaxPolicy,
+    /// The most `refill_interval`s a single refill catches up on, `None` for no limit
+    /// A key idle for longer recovers over several calls, as `last_updated` only moves forward by
+    /// the intervals applied. Denies while it catches up still store the intervals applied, so each
+    /// retry gets further, and report a `retry_after` of 0
+    pub max_intervals_per_refill: Option<u64>,
+    /// How accrued tokens are counted, `TokenAccounting::WholeIntervals` by default
+    pub accounting: TokenAccounting,
+    /// Called with the id, cost and result of every `limit` call that reaches a decision, before it returns
+    /// Runs inline, so anything slow should be handed off elsewhere
+    pub on_decision: Option<DecisionHook>,
+    /// Called with the tokens every `limit` call spent, zero for a deny, for usage metering
+    /// Also called with the tokens given back by `refund`, settled or dropped `Reservation`s and
+    /// rolled back `Transaction`s, and with what a `Reservation` spent over its estimate
+    pub on_consumed: Option<ConsumptionHook>,
+    /// Called with the id whenever a write loses to a newer one, a sign the key is contended
+    pub on_conflict: Option<ConflictHook>,
+    conflicts: AtomicU64,
+    /// Refunds from dropped `Reservation`s, applied the next time their id is limited or refunded
+    pending_refunds: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// How many tokens `TokenBucket` gives a key the first time it's limited
+pub enum SeedPolicy {
+    /// The resolved settings' `starting_tokens`
+    #[default]
+    StartingTokens,
+    /// `max_tokens` for keys with settings of their own, so explicitly configured keys start full,
+    /// and `starting_tokens` for keys using `default_settings`.
+    /// A degraded `FallbackClient` reports its fallback settings as the key's own
+    FullWhenConfigured,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// How `TokenBucket` treats a bucket whose `max_tokens` went up, e.g. after a quota increase
+///
+/// Unless this is `Ignore`, every write records the bucket's `max_tokens` as `RateLimitItem::cap`
+/// and an increase is detected against it. Buckets written before it was enabled have no cap,
+/// so they're only tracked from their next write on. The top up is applied after the refill and is never more than the
+/// new `max_tokens`. Increases from the global multiplier count as well
+pub enum QuotaIncrease {
+    /// Buckets only grow into a higher `max_tokens` by refilling
+    #[default]
+    Ignore,
+    /// Raise the bucket to the new `starting_tokens` if it holds less
+    TopUpToStarting,
+    /// Add the increase in `max_tokens` to the bucket
+    GrantDelta,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// How `TokenBucket` treats a bucket holding more than `max_tokens`, e.g. after a grant,
+/// a refund or a lowered limit
+pub enum OverMaxPolicy {
+    /// Leave it to the refill policy, `LinearRefill` clamps it to `max_tokens` on the next refill
+    #[default]
+    ClampImmediately,
+    /// Keep the excess until it's spent, nothing is refilled while the bucket is over `max_tokens`
+    /// This overrides the refill policy for such buckets, including `DecayPolicy`'s decay
+    DecayBySpending,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// How `TokenBucket` counts the tokens a bucket accrues between refills
+pub enum TokenAccounting {
+    /// Add `refill_rate` whole tokens per whole `refill_interval` with the refill policy
+    /// `last_updated` only moves by whole intervals, so a bucket refills in steps of `refill_rate`
+    #[default]
+    WholeIntervals,
+    /// Accrue `refill_rate / refill_interval` tokens every second, in millionths of a token
+    /// carried in `RateLimitItem::fraction`, and move `last_updated` to every refill
+    /// Callers still see whole tokens. The refill is always linear, the refill policy isn't used.
+    /// Each refill drops less than a millionth of a token, none at all when `refill_interval`
+    /// divides `refill_rate * FRACTION_SCALE`
+    FixedPoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// The state of a bucket as returned by `TokenBucket::inspect`
+pub struct BucketInspection {
+    /// The tokens stored at `last_updated`, or `starting_tokens` for a bucket never written
+    pub stored_tokens: u64,
+    /// The tokens accrued since `last_updated` before clamping to `max_tokens`, the applied
+    /// intervals times `refill_rate`, or the whole tokens accrued under `TokenAccounting::FixedPoint`
+    pub refilled_tokens: u64,
+    /// The tokens the bucket holds now, after the refill policy and clamping
+    pub remaining: u64,
+    pub last_updated: u64,
+    /// The settings the bucket is limited with, after the global multiplier
+    pub settings: RateLimitSettings,
+}
+
+/// See `TokenBucket::on_decision`
+pub type DecisionHook = Box<dyn Fn(&str, u64, &LimitResult) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// The configuration of a `TokenBucket`, as returned by `TokenBucket::config`
+/// Hooks can't be serialized, so only whether each is set is included
+pub struct BucketConfig {
+    pub default_settings: RateLimitSettings,
+    #[cfg(feature = "tracing")]
+    pub stale_threshold_secs: Option<u64>,
+    pub max_key_length: usize,
+    pub multiplier: Option<f64>,
+    pub multiplier_rounding: Rounding,
+    pub seed_policy: SeedPolicy,
+    pub quota_increase: QuotaIncrease,
+    pub over_max: OverMaxPolicy,
+    pub max_intervals_per_refill: Option<u64>,
+    pub accounting: TokenAccounting,
+    pub on_decision: bool,
+    pub on_consumed: bool,
+    pub on_conflict: bool,
+}
+
+/// See `TokenBucket::on_conflict`
+pub type ConflictHook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// See `TokenBucket::on_consumed`
+pub type ConsumptionHook = Box<dyn Fn(&ConsumedUnits) + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// The tokens a call spent from or gave back to `key`, reported to `TokenBucket::on_consumed`
+pub struct ConsumedUnits {
+    pub tokens: u64,
+    pub key: String,
+    /// The unix time of the call
+    pub at: u64,
+    /// Whether `tokens` were given back rather than spent, so metering should subtract them
+    pub refund: bool,
+}
+
+/// The default for `TokenBucket::max_key_length`
+pub const DEFAULT_MAX_KEY_LENGTH: usize = 1024;
+
+impl<T: TokenBucketClient> TokenBucket<T> {
+    pub fn new(client: T, default_settings: RateLimitSettings) -> Result<Self, TokenBucketError> {
+        Ok(Self {
+            client,
+            refill_policy: LinearRefill,
+            default_settings,
+            #[cfg(feature = "tracing")]
+            stale_threshold_secs: None,
+            max_key_length: DEFAULT_MAX_KEY_LENGTH,
+            multiplier: None,
+            multiplier_rounding: Rounding::Down,
+            seed_policy: SeedPolicy::StartingTokens,
+            quota_increase: QuotaIncrease::Ignore,
+            over_max: OverMaxPolicy::ClampImmediately,
+            max_intervals_per_refill: None,
+            accounting: TokenAccounting::WholeIntervals,
+            on_decision: None,
+            on_consumed: None,
+            on_conflict: None,
+            conflicts: AtomicU64::new(0),
+            pending_refunds: Mutex::default(),
+        })
+    }
+}
+
+impl<T: TokenBucketClient, R: RefillPolicy> TokenBucket<T, R> {
+    /// Queue a refund to be applied the next time `id` is written
+    fn defer_refund(&self, id: &str, tokens: u64) {
+        let mut pending = lock(&self.pending_refunds);
+        let refund = pending.entry(id.into()).or_default();
+        *refund = refund.saturating_add(tokens);
+    }
+
+    /// The configuration this bucket limits with, e.g. to log at startup
+    ///
+    /// ```
+    /// use distributed_ratelimit::{
+    ///     rate_limit_settings, InMemoryClient, SeedPolicy, TokenBucket,
+    /// };
+    ///
+    /// let settings = rate_limit_settings!(
+    ///     max_tokens: 10, starting_tokens: 10, refill_rate: 1, refill_interval: 60
+    /// );
+    /// let mut bucket = TokenBucket::new(InMemoryClient::new(), settings).unwrap();
+    /// bucket.seed_policy = SeedPolicy::FullWhenConfigured;
+    /// bucket.on_conflict = Some(Box::new(|_| {}));
+    ///
+    /// let config = bucket.config();
+    /// assert_eq!(config.default_settings, settings);
+    /// assert_eq!(config.seed_policy, SeedPolicy::FullWhenConfigured);
+    /// assert!(config.on_conflict && !config.on_decision);
+    /// ```
+    pub fn config(&self) -> BucketConfig {
+        BucketConfig {
+            default_settings: self.default_settings,
+            #[cfg(feature = "tracing")]
+            stale_threshold_secs: self.stale_threshold_secs,
+            max_key_length: self.max_key_length,
+            multiplier: self.multiplier,
+            multiplier_rounding: self.multiplier_rounding,
+            seed_policy: self.seed_policy,
+            quota_increase: self.quota_increase,
+            over_max: self.over_max,
+            max_intervals_per_refill: self.max_intervals_per_refill,
+            accounting: self.accounting,
+            on_decision: self.on_decision.is_some(),
+            on_consumed: self.on_consumed.is_some(),
+            on_conflict: self.on_conflict.is_some(),
+        }
+    }
+
+    /// How many writes have lost the conditional check to a newer write since this bucket was made
+    /// Those calls were still decided, against a bucket another request had already moved on
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+
+    /// Queue `tokens` to be given back to `id` later, reporting them as refunded now
+    pub(crate) fn queue_refund(&self, id: &str, tokens: u64) {
+        self.defer_refund(id, tokens);
+        self.report_consumed(id, tokens, current_unix_time(), true);
+    }
+
+    /// Call `on_consumed` for `tokens` spent from or refunded to `id` at `at`
+    fn report_consumed(&self, id: &str, tokens: u64, at: u64, refund: bool) {
+        if let Some(on_consumed) = &self.on_consumed {
+            on_consumed(&ConsumedUnits {
+                tokens,
+                key: id.into(),
+                at,
+                refund,
+            });
+        }
+    }
+
+    fn take_deferred_refund(&self, id: &str) -> u64 {
+        lock(&self.pending_refunds).remove(id).unwrap_or_default()
+    }
+}
+
+impl<T: TokenBucketClient, R: RefillPolicy> TokenBucket<T, R>
+where
+    T::Error: From<TokenBucketError>,
+{
+    /// Replace how tokens are refilled, `LinearRefill` by default
+    pub fn with_refill_policy<P: RefillPolicy>(self, refill_policy: P) -> TokenBucket<T, P> {
+        TokenBucket {
+            client: self.client,
+            refill_policy,
+            default_settings: self.default_settings,
+            #[cfg(feature = "tracing")]
+            stale_threshold_secs: self.stale_threshold_secs,
+            max_key_length: self.max_key_length,
+            multiplier: self.multiplier,
+            multiplier_rounding: self.multiplier_rounding,
+            seed_policy: self.seed_policy,
+            quota_increase: self.quota_increase,
+            over_max: self.over_max,
+            max_intervals_per_refill: self.max_intervals_per_refill,
+            accounting: self.accounting,
+            on_decision: self.on_decision,
+            on_consumed: self.on_consumed,
+            on_conflict: self.on_conflict,
+            conflicts: self.conflicts,
+            pending_refunds: self.pending_refunds,
+        }
+    }
+
+    /// Spend `estimate` tokens now for work whose final cost isn't known yet
+    /// Settle the reservation with `Reservation::commit` once the actual cost is known,
+    /// or `Reservation::cancel` to give the whole estimate back
+    pub async fn reserve(
+        &self,
+        id: &str,
+        estimate: u64,
+    ) -> Result<Reservation<'_, T, R>, T::Error> {
+        let result = self.limit(id, estimate).await?;
+        Ok(Reservation::new(self, id, estimate, result))
+    }
+
+    /// Start a transaction limiting several ids with their own costs
+    /// Add limits with `Transaction::add` and decide them with `Transaction::execute`
+    pub fn transaction(&self) -> Transaction<'_, T, R> {
+        Transaction::new(self)
+    }
+
+    /// Give `tokens` back to `id`
+    /// The tokens are added atomically on backends that support it, so concurrent refunds all count.
+    /// The stored count can briefly exceed `max_tokens` and is clamped on the next refill,
+    /// unless `over_max` is `OverMaxPolicy::DecayBySpending`
+    pub async fn refund(&self, id: &str, tokens: u64) -> Result<(), T::Error> {
+        self.validate_key(id)?;
+        let deferred = self.take_deferred_refund(id);
+        let result = self
+            .client
+            .refund(id, tokens.saturating_add(deferred))
+            .await;
+        match result {
+            // Deferred refunds were reported when they were queued
+            Ok(()) => self.report_consumed(id, tokens, current_unix_time(), true),
+            Err(_) if deferred > 0 => self.defer_refund(id, deferred),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Spend up to `tokens` from `id` regardless of what it holds, for work that already happened
+    /// Subtracted atomically on backends that support it, like `refund`
+    pub(crate) async fn force_spend(&self, id: &str, tokens: u64) -> Result<(), T::Error> {
+        self.validate_key(id)?;
+        self.client.spend(id, tokens).await?;
+        self.report_consumed(id, tokens, current_unix_time(), false);
+        Ok(())
+    }
+
+    /// Write `limit`, requeueing any deferred refund it includes if the write doesn't land
+    async fn store(
+        &self,
+        id: &str,
+        limit: RateLimitItem,
+        refunded: u64,
+    ) -> Result<PutOutcome, T::Error> {
+        let result = self.client.put_limit(id, limit).await;
+        if let Ok(PutOutcome::Conflict { .. }) = result {
+            self.conflicts.fetch_add(1, Ordering::Relaxed);
+            if let Some(on_conflict) = &self.on_conflict {
+                on_conflict(id);
+            }
+        }
+        if refunded > 0 && !matches!(result, Ok(PutOutcome::Written)) {
+            self.defer_refund(id, refunded);
+        }
+        result
+    }
+
+    /// Seconds since the stored limit for `id` was last refilled, `None` if it has never been written
+    /// `last_updated` sits on the latest refill boundary, so this can exceed the time since the
+    /// last write by up to one `refill_interval` unless `accounting` is `TokenAccounting::FixedPoint`
+    pub async fn item_age(&self, id: &str) -> Result<Option<u64>, T::Error> {
+        self.validate_key(id)?;
+        let (limit, _) = self.client.get_raw(id).await?;
+        let now = current_unix_time();
+        Ok(limit.map(|limit| now.saturating_sub(limit.last_updated)))
+    }
+
+    /// The tokens each named sub-bucket of `id` currently holds, refilled with the id's settings
+    /// Empty if `id` has no sub-buckets or the backend doesn't store them
+    pub async fn sub_buckets(&self, id: &str) -> Result<Vec<(String, u64)>, T::Error> {
+        self.validate_key(id)?;
+        let sub_buckets = self.client.get_sub_buckets(id).await?;
+        if sub_buckets.is_empty() {
+            return Ok(Vec::new());
+        }
+        let settings = self.client.get_settings(id).await?;
+        let settings = self.effective_settings(settings.unwrap_or(self.default_settings));
+        let now = current_unix_time();
+        Ok(sub_buckets
+            .into_iter()
+            .map(|(name, limit)| (name, self.refill(limit, &settings, now).tokens))
+            .collect())
+    }
+
+    /// Whether the bucket for `id` currently holds `max_tokens`, without spending anything
+    /// An id that has never been limited holds what `seed_policy` gives it
+    pub async fn is_full(&self, id: &str) -> Result<bool, T::Error> {
+        let (limit, settings) = self.current(id, None).await?;
+        Ok(limit.tokens >= settings.max_tokens)
+    }
+
+    /// The tokens `id` currently holds, without spending anything
+    /// An id that has never been limited holds what `seed_policy` gives it
+    pub async fn peek(&self, id: &str) -> Result<u64, T::Error> {
+        let (limit, _) = self.current(id, None).await?;
+        Ok(limit.tokens)
+    }
+
+    /// The bucket for `id` refilled to now and the settings it was refilled with, without writing
+    async fn current(
+        &self,
+        id: &str,
+        shard_settings: Option<ShardSettings>,
+    ) -> Result<(RateLimitItem, RateLimitSettings), T::Error> {
+        // Shard keys were checked as their logical id, see `decide`
+        if shard_settings.is_none() {
+            self.validate_key(id)?;
+        }
+        let now = current_unix_time();
+        let (limit, settings) = self.resolve(id, now, shard_settings).await?;
+        Ok((self.refill(limit, &settings, now), settings))
+    }
+
+    /// The stored limit and settings for `id`, or the defaults with a new bucket anchored at `now`
+    /// The settings have the global multiplier applied, and a new bucket is seeded from them
+    /// according to `seed_policy`. `shard_settings` replaces the stored settings for the shards
+    /// of a sharded limit, which take theirs from the logical id
+    async fn resolve(
+        &self,
+        id: &str,
+        now: u64,
+        shard_settings: Option<ShardSettings>,
+    ) -> Result<(RateLimitItem, RateLimitSettings), T::Error> {
+        let (limit, stored_settings) = self.client.get_raw(id).await?;
+        let (settings, configured) = match shard_settings {
+            Some(shard) => (shard.settings, shard.configured),
+            None => (
+                stored_settings.unwrap_or(self.default_settings),
+                stored_settings.is_some(),
+            ),
+        };
+        let settings = self.effective_settings(settings);
+        let tokens = match self.seed_policy {
+            SeedPolicy::FullWhenConfigured if configured => settings.max_tokens,
+            _ => settings.starting_tokens,
+        };
+        let limit = limit.unwrap_or(RateLimitItem {
+            last_updated: now,
+            tokens,
+            cap: None,
+            fraction: 0,
+        });
+        Ok((limit, settings))
+    }
+
+    /// Everything `limit` would work from for `id` right now, without spending or writing
+    pub async fn inspect(&self, id: &str) -> Result<BucketInspection, T::Error> {
+        self.validate_key(id)?;
+        let now = current_unix_time();
+        let (limit, settings) = self.resolve(id, now, None).await?;
+        let refilled = self.refill_accrued(limit, &settings, now);
+        Ok(BucketInspection {
+            stored_tokens: limit.tokens,
+            refilled_tokens: refilled.accrued,
+            remaining: refilled.limit.tokens,
+            last_updated: limit.last_updated,
+            settings,
+        })
+    }
+
+    /// Project `limit` forward to `now` with the refill policy
+    fn refill(
+        &self,
+        limit: RateLimitItem,
+        settings: &RateLimitSettings,
+        now: u64,
+    ) -> RateLimitItem {
+        self.refill_accrued(limit, settings, now).limit
+    }
+
+    /// `refill`, along with what was accrued
+    fn refill_accrued(
+        &self,
+        limit: RateLimitItem,
+        settings: &RateLimitSettings,
+        now: u64,
+    ) -> Refilled {
+        let mut refilled = match self.accounting {
+            TokenAccounting::WholeIntervals => self.refill_whole_intervals(limit, settings, now),
+            TokenAccounting::FixedPoint => self.refill_fixed_point(limit, settings, now),
+        };
+        self.apply_quota_increase(&mut refilled.limit, settings);
+        refilled
+    }
+
+    fn refill_whole_intervals(
+        &self,
+        mut limit: RateLimitItem,
+        settings: &RateLimitSettings,
+        now: u64,
+    ) -> Refilled {
+        let elapsed = elapsed_intervals(&limit, settings, now);
+        let mut intervals = elapsed;
+        let mut now = now;
+        if let Some(max_intervals) = self.max_intervals_per_refill {
+            if intervals > max_intervals {
+                intervals = max_intervals;
+                // The policy measures elapsed time itself, so show it only the applied intervals
+                now = limit.last_updated + intervals * settings.refill_interval.get();
+            }
+        }
+        limit.tokens = match self.over_max {
+            OverMaxPolicy::DecayBySpending if limit.tokens > settings.max_tokens => limit.tokens,
+            _ => self.refill_policy.refill(&limit, settings, now),
+        };
+        // Only move forward by whole intervals so partial progress towards the next refill is kept
+        limit.last_updated += intervals * settings.refill_interval.get();
+        Refilled {
+            limit,
+            accrued: intervals.saturating_mul(settings.refill_rate),
+            capped: intervals < elapsed,
+        }
+    }
+
+    fn refill_fixed_point(
+        &self,
+        mut limit: RateLimitItem,
+        settings: &RateLimitSettings,
+        now: u64,
+    ) -> Refilled {
+        let interval = settings.refill_interval.get();
+        let elapsed = now.saturating_sub(limit.last_updated);
+        let applied = match self.max_intervals_per_refill {
+            Some(max_intervals) => cmp::min(elapsed, max_intervals.saturating_mul(interval)),
+            None => elapsed,
+        };
+        let scale = u128::from(FRACTION_SCALE);
+        // The division only drops what's left under a millionth of a token
+        let millionths = (u128::from(applied) * u128::from(settings.refill_rate))
+            .saturating_mul(scale)
+            / u128::from(interval)
+            + u128::from(limit.fraction);
+        let accrued = u64::try_from(millionths / scale).unwrap_or(u64::MAX);
+        match self.over_max {
+            OverMaxPolicy::DecayBySpending if limit.tokens > settings.max_tokens => {}
+            _ => match limit.tokens.saturating_add(accrued) {
+                tokens if tokens >= settings.max_tokens => {
+                    limit.tokens = settings.max_tokens;
+                    limit.fraction = 0;
+                }
+                tokens => {
+                    limit.tokens = tokens;
+                    limit.fraction = (millionths % scale) as u64;
+                }
+            },
+        }
+        limit.last_updated += applied;
+        Refilled {
+            limit,
+            accrued,
+            capped: applied < elapsed,
+        }
+    }
+
+    /// Top up `limit` if `max_tokens` has grown since it was written, and record the current cap
+    fn apply_quota_increase(&self, limit: &mut RateLimitItem, settings: &RateLimitSettings) {
+        if self.quota_increase == QuotaIncrease::Ignore {
+            return;
+        }
+        if let Some(cap) = limit.cap.filter(|cap| settings.max_tokens > *cap) {
+            let topped_up = match self.quota_increase {
+                QuotaIncrease::Ignore => limit.tokens,
+                QuotaIncrease::TopUpToStarting => settings.starting_tokens,
+                QuotaIncrease::GrantDelta => limit.tokens.saturating_add(settings.max_tokens - cap),
+            };
+            limit.tokens = cmp::max(limit.tokens, cmp::min(topped_up, settings.max_tokens));
+        }
+        limit.cap = Some(settings.max_tokens);
+    }
+
+    /// Apply the global multiplier to the settings resolved for a key
+    fn effective_settings(&self, settings: RateLimitSettings) -> RateLimitSettings {
+        match self.multiplier {
+            Some(factor) => settings.scaled(factor, self.multiplier_rounding),
+            None => settings,
+        }
+    }
+
+    /// Reject ids that would either share a bucket with every other empty id or be too large to store
+    fn validate_key(&self, id: &str) -> Result<(), TokenBucketError> {
+        if id.is_empty() {
+            return Err(TokenBucketError::InvalidKey("id is empty".into()));
+        }
+        if id.len() > self.max_key_length {
+            return Err(TokenBucketError::InvalidKey(format!(
+                "id is {} bytes, the max is {}",
+                id.len(),
+                self.max_key_length
+            )));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    fn warn_if_stale(&self, id: &str, limit: &RateLimitItem, now: u64) {
+        let elapsed = now.saturating_sub(limit.last_updated);
+        if self
+            .stale_threshold_secs
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            tracing::warn!(
+                id,
+                last_updated = limit.last_updated,
+                elapsed,
+                max_intervals_per_refill = self.max_intervals_per_refill,
+                "stale rate limit bucket, refilling it from an old last_updated"
+            );
+        }
+    }
+
+    /// Spend `cost` tokens from `id` if it holds enough, otherwise deny without spending anything
+    ///
+    /// The settings are resolved on every call, so a key whose SETTINGS row disappears is limited
+    /// with `default_settings` from then on. If that leaves the bucket over the new `max_tokens`,
+    /// `LinearRefill` clamps it down on this call even when no interval has passed. The bucket is
+    /// not re-anchored: `last_updated` is kept, so partial progress towards the next refill carries
+    /// over to the new `refill_interval`
     pub async fn limit(&self, id: &str, cost: u64) -> Result<LimitResult, T::Error> {
-        let (mut limit, settings) = self.client.get(id, self.default_settings).await?;
+        let (result, _) = self
+            .limit_inner(id, cost, current_unix_time(), false)
+            .await?;
+        Ok(result)
+    }
+
+    /// `limit`, along with the time spent waiting on the backend to read and write the bucket
+    pub async fn limit_timed(
+        &self,
+        id: &str,
+        cost: u64,
+    ) -> Result<(LimitResult, Duration), T::Error> {
+        self.limit_inner(id, cost, current_unix_time(), false).await
+    }
+
+    /// Limit `id` as of the unix time `at` instead of now, to replay or backfill past events
+    /// Events for an id have to be replayed in order: `at` before the stored `last_updated`
+    /// fails with `TokenBucketError::OutOfOrder`, and the conditional write drops the result
+    /// if a newer write landed in the meantime
+    pub async fn limit_at(&self, id: &str, cost: u64, at: u64) -> Result<LimitResult, T::Error> {
+        let (result, _) = self.limit_inner(id, cost, at, true).await?;
+        Ok(result)
+    }
+
+    async fn limit_inner(
+        &self,
+        id: &str,
+        cost: u64,
+        now: u64,
+        replaying: bool,
+    ) -> Result<(LimitResult, Duration), T::Error> {
+        let (result, backend_time) = self.decide(id, cost, now, replaying, None).await?;
+        self.report(id, cost, &result, now);
+        Ok((result, backend_time))
+    }
 
+    /// Limit one logical `id` split across `sharding.shards` keys, so its writes are spread over
+    /// several DynamoDB partitions. Each call spends from a single shard, `{id}#shard{n}`
+    ///
+    /// Every shard is limited with the settings of `id` split by `RateLimitSettings::per_node`,
+    /// read with `get_settings` on every call, which `CachedSettingsClient` can serve locally.
+    /// This is less accurate than a single bucket:
+    /// - A call can be denied by its shard while other shards still have tokens
+    /// - A cost above a shard's `max_tokens` is never allowed, even if the whole limit could afford it
+    /// - Shard limits are rounded up, so the shards together can allow up to `shards - 1` more tokens
+    ///
+    /// Hooks are called with `id`, not the shard key. `max_key_length` applies to `id`, the
+    /// shard suffix doesn't count against it
+    pub async fn limit_sharded(
+        &self,
+        id: &str,
+        cost: u64,
+        sharding: &Sharding,
+    ) -> Result<LimitResult, T::Error> {
+        self.validate_key(id)?;
+        let settings = self.shard_settings(id, sharding).await?;
         let now = current_unix_time();
-        //todo check divide by zero
-        let intervals = now
-            .saturating_sub(limit.last_updated)
-            .saturating_div(settings.refill_interval.into());
+        let shard = sharding.shard_key(id, sharding.pick());
+        let (result, _) = self
+            .decide(&shard, cost, now, false, Some(settings))
+            .await?;
+        self.report(id, cost, &result, now);
+        Ok(result)
+    }
 
-        let refilled_tokens = intervals * settings.refill_rate;
-        limit.tokens = cmp::min(settings.max_tokens, limit.tokens + refilled_tokens);
+    /// The tokens all shards of a sharded `id` hold together, see `limit_sharded`
+    pub async fn peek_sharded(&self, id: &str, sharding: &Sharding) -> Result<u64, T::Error> {
+        self.validate_key(id)?;
+        let settings = self.shard_settings(id, sharding).await?;
+        let mut remaining = 0u64;
+        for shard in 0..sharding.shards.get() {
+            let shard = sharding.shard_key(id, shard);
+            let (limit, _) = self.current(&shard, Some(settings)).await?;
+            remaining = remaining.saturating_add(limit.tokens);
+        }
+        Ok(remaining)
+    }
+
+    /// The settings each shard of `id` is limited with
+    async fn shard_settings(
+        &self,
+        id: &str,
+        sharding: &Sharding,
+    ) -> Result<ShardSettings, T::Error> {
+        let settings = self.client.get_settings(id).await?;
+        Ok(ShardSettings {
+            settings: settings
+                .unwrap_or(self.default_settings)
+                .per_node(sharding.shards),
+            configured: settings.is_some(),
+        })
+    }
+
+    /// Call the decision hooks for a `limit` of `id` decided at `now`
+    fn report(&self, id: &str, cost: u64, result: &LimitResult, now: u64) {
+        if let Some(on_decision) = &self.on_decision {
+            on_decision(id, cost, result);
+        }
+        let tokens = match result {
+            LimitResult::Allow { .. } => cost,
+            LimitResult::Deny { .. } => 0,
+        };
+        self.report_consumed(id, tokens, now, false);
+    }
+
+    /// Limit each of `ids` by `cost`, one after another
+    /// Stops at the first error, by which point the ids before it have already been spent from
+    pub async fn limit_many(&self, ids: &[&str], cost: u64) -> Result<BatchLimitResult, T::Error> {
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            results.push(self.limit(id, cost).await?);
+        }
+        Ok(BatchLimitResult {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            results,
+        })
+    }
+
+    /// Limit a request of `bytes` bytes, with the cost worked out by `policy`
+    pub async fn limit_sized(
+        &self,
+        id: &str,
+        bytes: u64,
+        policy: &impl CostPolicy,
+    ) -> Result<LimitResult, T::Error> {
+        self.limit(id, policy.cost(bytes)).await
+    }
+
+    async fn decide(
+        &self,
+        id: &str,
+        cost: u64,
+        now: u64,
+        replaying: bool,
+        shard_settings: Option<ShardSettings>,
+    ) -> Result<(LimitResult, Duration), T::Error> {
+        let mut backend_time = Duration::ZERO;
+        // A shard key was checked as its logical id, the suffix isn't held against max_key_length
+        if shard_settings.is_none() {
+            self.validate_key(id)?;
+        }
+        let (limit, settings) =
+            timed(&mut backend_time, self.resolve(id, now, shard_settings)).await?;
+
+        if replaying && now < limit.last_updated {
+            return Err(TokenBucketError::OutOfOrder {
+                at: now,
+                last_updated: limit.last_updated,
+            }
+            .into());
+        }
+
+        #[cfg(feature = "tracing")]
+        self.warn_if_stale(id, &limit, now);
+
+        let Refilled {
+            mut limit, capped, ..
+        } = self.refill_accrued(limit, &settings, now);
+        let refunded = self.take_deferred_refund(id);
+        limit.tokens = credit(limit.tokens, refunded, settings.max_tokens);
 
         if limit.tokens < cost {
-            return Ok(LimitResult::Deny);
+            if refunded > 0 || capped {
+                // Nothing is spent, but a deferred refund or a capped refill still has to be
+                // stored, or the next call would catch up from the same old last_updated again
+                timed(&mut backend_time, self.store(id, limit, refunded)).await?;
+            }
+            let retry_after = retry_after(&limit, &settings, cost, now, self.accounting);
+            let result = LimitResult::Deny {
+                retry_after,
+                allowed_at: now.saturating_add(retry_after),
+                recoverable: settings.can_ever_allow(cost),
+            };
+            return Ok((result, backend_time));
         }
 
         limit.tokens = limit.tokens.saturating_sub(cost);
         let remaining = limit.tokens;
 
-        self.client.put_limit(id, limit).await?;
-        Ok(LimitResult::Allow { remaining })
+        timed(&mut backend_time, self.store(id, limit, refunded)).await?;
+        Ok((LimitResult::Allow { remaining }, backend_time))
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+/// A bucket refilled to now, see `TokenBucket::refill_accrued`
+struct Refilled {
+    limit: RateLimitItem,
+    /// The whole tokens accrued before clamping to `max_tokens`
+    accrued: u64,
+    /// Whether `max_intervals_per_refill` stopped the refill short of now
+    capped: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// The settings the shards of a sharded id are limited with, see `TokenBucket::limit_sharded`
+struct ShardSettings {
+    settings: RateLimitSettings,
+    /// Whether the logical id has settings of its own, which `SeedPolicy` seeds the shards by
+    configured: bool,
+}
+
+/// Add `refund` to `tokens` without going over `max_tokens`
+/// Tokens already over `max_tokens` are left alone rather than clamped
+fn credit(tokens: u64, refund: u64, max_tokens: u64) -> u64 {
+    cmp::max(tokens, cmp::min(tokens.saturating_add(refund), max_tokens))
+}
+
+/// How long until `limit` will have refilled enough to afford `cost`
+fn retry_after(
+    limit: &RateLimitItem,
+    settings: &RateLimitSettings,
+    cost: u64,
+    now: u64,
+    accounting: TokenAccounting,
+) -> u64 {
+    if !settings.can_ever_allow(cost) {
+        return u64::MAX;
+    }
+    let interval = settings.refill_interval.get();
+    let wait = match accounting {
+        TokenAccounting::WholeIntervals => {
+            let missing = cost.saturating_sub(limit.tokens);
+            missing
+                .div_ceil(settings.refill_rate)
+                .saturating_mul(interval)
+        }
+        TokenAccounting::FixedPoint => {
+            let scale = u128::from(FRACTION_SCALE);
+            let held = u128::from(limit.tokens) * scale + u128::from(limit.fraction);
+            let missing = (u128::from(cost) * scale).saturating_sub(held);
+            let wait = missing
+                .saturating_mul(u128::from(interval))
+                .div_ceil(u128::from(settings.refill_rate) * scale);
+            u64::try_from(wait).unwrap_or(u64::MAX)
+        }
+    };
+    // last_updated sits on the most recent refill, so count from there
+    limit.last_updated.saturating_add(wait).saturating_sub(now)
+}
+
+/// Run `future`, adding the time it took to `elapsed`
+async fn timed<O>(elapsed: &mut Duration, future: impl std::future::Future<Output = O>) -> O {
+    let start = Instant::now();
+    let output = future.await;
+    *elapsed += start.elapsed();
+    output
+}
+
+/// Lock `mutex`, even if a panic elsewhere poisoned it
+/// Everything behind these locks is only changed in single steps while locked, so it can't
+/// have been left half updated
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 pub(crate) fn current_unix_time() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -235,8 +2263,1270 @@ pub(crate) fn current_unix_time() -> u64 {
 pub enum TokenBucketError {
     #[error("Failed to get")]
     DynamoGet(#[from] SdkError<QueryError, Response<SdkBody>>),
+    #[error("Failed to get item")]
+    DynamoGetItem(#[from] SdkError<GetItemError, Response<SdkBody>>),
+    #[error("Failed to batch get")]
+    DynamoBatchGet(#[from] SdkError<BatchGetItemError, Response<SdkBody>>),
+    #[error("Failed to build the dynamodb request")]
+    DynamoBuild(#[from] BuildError),
     #[error("Failed to Update")]
     DynamoPut(#[from] SdkError<PutItemError, Response<SdkBody>>),
+    #[error("Failed to update item")]
+    DynamoUpdate(#[from] SdkError<UpdateItemError, Response<SdkBody>>),
     #[error("Failed to serialize/deserialize the dynamodb item")]
     SerdeError(#[from] serde_dynamo::Error),
+    #[error("DynamoDB left {} keys unprocessed after retrying", .0.len())]
+    UnprocessedKeys(Vec<String>),
+    #[error("Invalid rate limit key: {0}")]
+    InvalidKey(String),
+    #[error(
+        "A rate of {requested} tokens per second can't be represented, the closest is {effective}"
+    )]
+    InexactRate { requested: f64, effective: f64 },
+    #[error("Invalid settings attribute names: {0}")]
+    InvalidSettingsAttributes(String),
+    #[error("Failed to read or write the settings snapshot")]
+    Snapshot(#[from] serde_json::Error),
+    #[error("Unsupported settings snapshot version {0}")]
+    SnapshotVersion(u32),
+    #[error("Replayed time {at} is before the stored last_updated {last_updated}")]
+    OutOfOrder { at: u64, last_updated: u64 },
+    #[error("The query returned more than one {0} row")]
+    DuplicateRow(String),
+    #[error("Invalid rate limit settings")]
+    InvalidSettings(#[from] InvalidSettings),
+    #[error("The DynamoDB call didn't finish within {0:?}")]
+    Timeout(Duration),
+    #[error("The row was written with schema version {0}, newer than this version can read")]
+    SchemaVersion(u32),
+    #[error("A backend timeout needs a DynamoDB client with a sleep_impl to time calls with")]
+    NoSleepImpl,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{bucket, settings, FlakyClient, MockDynamo, TokioSleep};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn rejects_empty_and_too_long_ids() {
+        let (mut bucket, _) = bucket(settings(10, 10, 1, 60));
+        bucket.max_key_length = 8;
+
+        let empty = bucket.limit("", 1).await;
+        assert!(matches!(empty, Err(TokenBucketError::InvalidKey(_))));
+        let too_long = bucket.limit("012345678", 1).await;
+        assert!(matches!(too_long, Err(TokenBucketError::InvalidKey(_))));
+        let refund = bucket.refund("012345678", 1).await;
+        assert!(matches!(refund, Err(TokenBucketError::InvalidKey(_))));
+
+        let longest = bucket.limit("01234567", 1).await.unwrap();
+        assert_eq!(longest, LimitResult::Allow { remaining: 9 });
+    }
+
+    /// Refills to `max_tokens` as soon as a whole interval has passed
+    struct FullEveryInterval;
+
+    impl RefillPolicy for FullEveryInterval {
+        fn refill(&self, item: &RateLimitItem, settings: &RateLimitSettings, now: u64) -> u64 {
+            match elapsed_intervals(item, settings, now) {
+                0 => item.tokens,
+                _ => settings.max_tokens,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn uses_a_custom_refill_policy() {
+        let (bucket, client) = bucket(settings(10, 10, 1, 60));
+        let bucket = bucket.with_refill_policy(FullEveryInterval);
+        let t0 = 1_000_000;
+
+        assert_eq!(
+            bucket.limit_at("a", 10, t0).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+        let denied = bucket.limit_at("a", 1, t0 + 59).await.unwrap();
+        assert!(matches!(denied, LimitResult::Deny { .. }));
+        // LinearRefill would only have added one token
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 90).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+        let (limit, _) = client.get_raw("a").await.unwrap();
+        assert_eq!(limit.unwrap().last_updated, t0 + 60);
+    }
+
+    #[tokio::test]
+    async fn allowed_at_is_now_plus_retry_after() {
+        let (bucket, _) = bucket(settings(10, 2, 2, 60));
+        let t0 = 1_000_000;
+        bucket.limit_at("a", 1, t0).await.unwrap();
+
+        // Missing 4 tokens at 2 per interval is two intervals away
+        let denied = bucket.limit_at("a", 5, t0).await.unwrap();
+        assert_eq!(
+            denied,
+            LimitResult::Deny {
+                retry_after: 120,
+                allowed_at: t0 + 120,
+                recoverable: true,
+            }
+        );
+        // Partway into an interval the remaining wait shrinks, but the time it's allowed at doesn't
+        let LimitResult::Deny {
+            retry_after,
+            allowed_at,
+            ..
+        } = bucket.limit_at("a", 5, t0 + 30).await.unwrap()
+        else {
+            panic!("expected a deny");
+        };
+        assert_eq!((retry_after, allowed_at), (90, t0 + 120));
+        assert_eq!(
+            bucket.limit_at("a", 5, allowed_at).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+    }
+
+    #[test]
+    fn scaled_rounds_down_and_up() {
+        let original = settings(9, 9, 3, 60);
+
+        let down = original.scaled(0.5, Rounding::Down);
+        assert_eq!((down.max_tokens, down.refill_rate), (4, 1));
+        let up = original.scaled(0.5, Rounding::Up);
+        assert_eq!((up.max_tokens, up.refill_rate), (5, 2));
+        // The interval is never scaled, and starting_tokens only comes down to the new max
+        assert_eq!(
+            (down.refill_interval.get(), up.refill_interval.get()),
+            (60, 60)
+        );
+        assert_eq!((down.starting_tokens, up.starting_tokens), (4, 5));
+        let partial = settings(9, 2, 3, 60).scaled(0.5, Rounding::Down);
+        assert_eq!(partial.starting_tokens, 2);
+    }
+
+    #[tokio::test]
+    async fn multiplier_applies_to_new_buckets() {
+        let (mut bucket, client) = bucket(settings(100, 100, 10, 60));
+        bucket.multiplier = Some(0.5);
+        // Keeping tokens over max_tokens must not keep an unscaled seed either
+        bucket.over_max = OverMaxPolicy::DecayBySpending;
+
+        let denied = bucket.limit("a", 100).await.unwrap();
+        assert!(matches!(denied, LimitResult::Deny { .. }));
+        assert_eq!(
+            bucket.limit("a", 50).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+
+        bucket.multiplier = Some(0.25);
+        bucket.multiplier_rounding = Rounding::Up;
+        bucket.seed_policy = SeedPolicy::FullWhenConfigured;
+        client
+            .put_settings("b", settings(10, 0, 1, 60))
+            .await
+            .unwrap();
+        assert_eq!(bucket.peek("b").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn item_age_of_fresh_and_absent_keys() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+        assert_eq!(bucket.item_age("a").await.unwrap(), None);
+
+        bucket.limit("a", 1).await.unwrap();
+        // Written this second, or the last if the clock ticked over since
+        let age = bucket.item_age("a").await.unwrap().unwrap();
+        assert!(age <= 1);
+    }
+
+    /// A BatchGetItem response with a SETTINGS row for each of `found` and `unprocessed` left over
+    fn batch_get_response(found: &[&str], unprocessed: &[&str]) -> serde_json::Value {
+        let row = |id: &str| {
+            serde_json::json!({
+                "pk": {"S": id},
+                "sk": {"S": "SETTINGS"},
+                "max_tokens": {"N": "10"},
+                "starting_tokens": {"N": "10"},
+                "refill_rate": {"N": "1"},
+                "refill_interval": {"N": "60"},
+            })
+        };
+        let key = |id: &str| serde_json::json!({"pk": {"S": id}, "sk": {"S": "SETTINGS"}});
+        let mut response = serde_json::json!({
+            "Responses": {"table": found.iter().map(|id| row(id)).collect::<Vec<_>>()},
+        });
+        if !unprocessed.is_empty() {
+            response["UnprocessedKeys"] = serde_json::json!({
+                "table": {"Keys": unprocessed.iter().map(|id| key(id)).collect::<Vec<_>>()},
+            });
+        }
+        response
+    }
+
+    /// The partition keys a BatchGetItem request asked for
+    fn requested_ids(body: &serde_json::Value) -> Vec<String> {
+        body["RequestItems"]["table"]["Keys"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|key| key["pk"]["S"].as_str().map(String::from))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn get_settings_many_retries_unprocessed_keys() {
+        let attempts = AtomicU64::new(0);
+        let mock = MockDynamo::new(move |_, body| {
+            // "b" is throttled on the first attempt only
+            let unprocessed: &[&str] = match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => &["b"],
+                _ => &[],
+            };
+            let ids = requested_ids(body);
+            let found: Vec<&str> = ids
+                .iter()
+                .map(String::as_str)
+                .filter(|id| *id != "c" && !unprocessed.contains(id))
+                .collect();
+            (200, batch_get_response(&found, unprocessed))
+        });
+        let client = mock.client("table");
+
+        let many = client.get_settings_many(&["a", "b", "c"]).await.unwrap();
+        assert_eq!(
+            many,
+            [
+                Some(settings(10, 10, 1, 60)),
+                Some(settings(10, 10, 1, 60)),
+                None
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requested_ids(&requests[1].1), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn get_settings_many_fails_once_retries_run_out() {
+        let mock = MockDynamo::new(|_, body| {
+            let ids = requested_ids(body);
+            let found: Vec<&str> = ids
+                .iter()
+                .map(String::as_str)
+                .filter(|id| *id != "b")
+                .collect();
+            (200, batch_get_response(&found, &["b"]))
+        });
+        let mut client = mock.client("table");
+        client.batch_get_retries = 2;
+
+        let result = client.get_settings_many(&["a", "b"]).await;
+        assert!(matches!(result, Err(TokenBucketError::UnprocessedKeys(keys)) if keys == ["b"]));
+        // The first attempt and two retries
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn is_full_for_absent_and_spent_keys() {
+        let (mut bucket, client) = bucket(settings(10, 5, 1, 60));
+        client
+            .put_settings("configured", settings(10, 5, 1, 60))
+            .await
+            .unwrap();
+
+        assert!(!bucket.is_full("default").await.unwrap());
+        assert!(!bucket.is_full("configured").await.unwrap());
+
+        bucket.seed_policy = SeedPolicy::FullWhenConfigured;
+        assert!(!bucket.is_full("default").await.unwrap());
+        assert!(bucket.is_full("configured").await.unwrap());
+
+        bucket.limit("configured", 1).await.unwrap();
+        assert!(!bucket.is_full("configured").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn copies_corrupt_rows_to_the_dead_letter_before_resetting_them() {
+        let mock = MockDynamo::new(|operation, _| match operation {
+            "Query" => {
+                let corrupt = serde_json::json!({
+                    "pk": {"S": "a"},
+                    "sk": {"S": "LIMIT"},
+                    "tokens": {"S": "not a number"},
+                    "last_updated": {"N": "1"},
+                });
+                (200, serde_json::json!({"Items": [corrupt], "Count": 1}))
+            }
+            _ => (200, serde_json::json!({})),
+        });
+        let mut client = mock.client("table");
+        client.dead_letter = Some(DeadLetter::SortKeySuffix("#CORRUPT".into()));
+        let bucket = TokenBucket::new(client, settings(10, 10, 1, 60)).unwrap();
+
+        assert_eq!(
+            bucket.limit("a", 1).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+        let requests = mock.requests();
+        let operations: Vec<&str> = requests.iter().map(|(op, _)| op.as_str()).collect();
+        assert_eq!(operations, ["Query", "PutItem", "PutItem"]);
+
+        let dead_letter = &requests[1].1;
+        assert_eq!(dead_letter["Item"]["sk"]["S"], "LIMIT#CORRUPT");
+        assert_eq!(dead_letter["Item"]["tokens"]["S"], "not a number");
+        assert!(dead_letter["Item"]["dead_lettered_at"]["N"].is_string());
+        assert_eq!(
+            dead_letter["ConditionExpression"],
+            "attribute_not_exists(#key)"
+        );
+        let reset = &requests[2].1;
+        assert_eq!(reset["Item"]["sk"]["S"], "LIMIT");
+        assert_eq!(reset["Item"]["tokens"]["N"], "9");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_first_dead_letter_copy() {
+        let mock = MockDynamo::new(|operation, _| match operation {
+            "GetItem" => {
+                let corrupt = serde_json::json!({
+                    "pk": {"S": "a"},
+                    "sk": {"S": "SETTINGS"},
+                    "max_tokens": {"S": "not a number"},
+                });
+                (200, serde_json::json!({"Item": corrupt}))
+            }
+            _ => (
+                400,
+                serde_json::json!({
+                    "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                    "message": "The conditional request failed",
+                }),
+            ),
+        });
+        let mut client = mock.client("table");
+        client.dead_letter = Some(DeadLetter::Table("dead-letters".into()));
+
+        // The copy from an earlier read is left alone and the row still reads as reset
+        assert_eq!(client.get_settings("a").await.unwrap(), None);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn settings_attributes_round_trip() {
+        let original = settings(10, 5, 2, 60);
+        // Two fields trade names, the other two are renamed outright
+        let attributes = SettingsAttributes {
+            max_tokens: "refill_interval".into(),
+            starting_tokens: "rl_start".into(),
+            refill_rate: "rl_rate".into(),
+            refill_interval: "max_tokens".into(),
+        };
+
+        let item: HashMap<String, AttributeValue> = to_item(original).unwrap();
+        let stored = attributes.to_stored(item);
+        let number = |n: &str| AttributeValue::N(n.into());
+        assert_eq!(
+            stored,
+            HashMap::from([
+                ("refill_interval".to_string(), number("10")),
+                ("rl_start".to_string(), number("5")),
+                ("rl_rate".to_string(), number("2")),
+                ("max_tokens".to_string(), number("60")),
+            ])
+        );
+        let decoded: RateLimitSettings = from_item(attributes.to_fields(stored)).unwrap();
+        assert_eq!(decoded, original);
+
+        let prefixed = SettingsAttributes::prefixed("rl_");
+        let item: HashMap<String, AttributeValue> = to_item(original).unwrap();
+        let stored = prefixed.to_stored(item);
+        assert_eq!(stored.get("rl_refill_interval"), Some(&number("60")));
+        let decoded: RateLimitSettings = from_item(prefixed.to_fields(stored)).unwrap();
+        assert_eq!(decoded, original);
+    }
+
+    #[tokio::test]
+    async fn on_decision_fires_once_per_decision() {
+        let (mut bucket, _) = bucket(settings(2, 2, 1, 60));
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = decisions.clone();
+        bucket.on_decision = Some(Box::new(move |id, cost, result| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((id.to_string(), cost, *result));
+        }));
+
+        let allowed = bucket.limit("a", 2).await.unwrap();
+        let denied = bucket.limit("a", 1).await.unwrap();
+        // Rejected before a decision is reached
+        assert!(bucket.limit("", 1).await.is_err());
+
+        assert_eq!(
+            *decisions.lock().unwrap(),
+            [("a".to_string(), 2, allowed), ("a".to_string(), 1, denied)]
+        );
+        assert!(matches!(denied, LimitResult::Deny { .. }));
+    }
+
+    #[tokio::test]
+    async fn concurrent_refunds_both_count() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+        bucket.limit("a", 5).await.unwrap();
+
+        let (first, second) = tokio::join!(bucket.refund("a", 1), bucket.refund("a", 1));
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(bucket.peek("a").await.unwrap(), 7);
+
+        // DynamoDB adds the tokens in the update instead of writing back a count it read
+        let mock = MockDynamo::new(|_, _| (200, serde_json::json!({})));
+        mock.client("table").refund("a", 1).await.unwrap();
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "UpdateItem");
+        assert_eq!(requests[0].1["UpdateExpression"], "ADD tokens :tokens");
+        assert_eq!(
+            requests[0].1["ExpressionAttributeValues"][":tokens"]["N"],
+            "1"
+        );
+    }
+
+    #[tokio::test]
+    async fn put_limit_reports_the_newer_limit_it_lost_to() {
+        let client = InMemoryClient::new();
+        let newer = RateLimitItem {
+            last_updated: 200,
+            tokens: 3,
+            cap: None,
+            fraction: 0,
+        };
+        let older = RateLimitItem {
+            last_updated: 100,
+            tokens: 9,
+            cap: None,
+            fraction: 0,
+        };
+        assert_eq!(
+            client.put_limit("a", newer).await.unwrap(),
+            PutOutcome::Written
+        );
+        assert_eq!(
+            client.put_limit("a", older).await.unwrap(),
+            PutOutcome::Conflict {
+                current: Some(newer)
+            }
+        );
+        assert_eq!(client.get_raw("a").await.unwrap().0, Some(newer));
+
+        let mock = MockDynamo::new(|_, _| {
+            let body = serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed",
+                "Item": {
+                    "pk": {"S": "a"},
+                    "sk": {"S": "LIMIT"},
+                    "tokens": {"N": "3"},
+                    "last_updated": {"N": "200"},
+                    "schema_version": {"N": "2"},
+                },
+            });
+            (400, body)
+        });
+        let mut client = mock.client("table");
+        client.return_conflicting_item = true;
+        assert_eq!(
+            client.put_limit("a", older).await.unwrap(),
+            PutOutcome::Conflict {
+                current: Some(newer)
+            }
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0].1["ReturnValuesOnConditionCheckFailure"],
+            "ALL_OLD"
+        );
+    }
+
+    #[tokio::test]
+    async fn limit_many_lists_denied_ids_in_order() {
+        let (bucket, client) = bucket(settings(10, 10, 1, 60));
+        client
+            .put_settings("b", settings(1, 1, 1, 60))
+            .await
+            .unwrap();
+        client
+            .put_settings("d", settings(1, 0, 1, 60))
+            .await
+            .unwrap();
+
+        let batch = bucket.limit_many(&["a", "b", "c", "d"], 2).await.unwrap();
+        assert_eq!(batch.denied().collect::<Vec<_>>(), ["b", "d"]);
+        assert_eq!(batch.iter().count(), 4);
+        assert_eq!(batch.results[0], LimitResult::Allow { remaining: 8 });
+
+        let batch = bucket.limit_many(&["a", "c"], 1).await.unwrap();
+        assert_eq!(batch.denied().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn sub_buckets_are_refilled_with_the_id_settings() {
+        let last_updated = current_unix_time() - 120;
+        let mock = MockDynamo::new(move |operation, body| {
+            let row = |sk: &str, tokens: u64| {
+                serde_json::json!({
+                    "pk": {"S": "a"},
+                    "sk": {"S": sk},
+                    "tokens": {"N": tokens.to_string()},
+                    "last_updated": {"N": last_updated.to_string()},
+                })
+            };
+            match operation {
+                "Query" if body["ExpressionAttributeValues"][":value"]["S"] == "a" => {
+                    let mut dead_letter = row("LIMIT#CORRUPT", 0);
+                    dead_letter["dead_lettered_at"] = serde_json::json!({"N": "1"});
+                    let items = [row("LIMIT#read", 1), row("LIMIT#write", 9), dead_letter];
+                    (200, serde_json::json!({"Items": items, "Count": 3}))
+                }
+                "Query" => (200, serde_json::json!({"Items": [], "Count": 0})),
+                // No settings of its own, so the defaults apply
+                _ => (200, serde_json::json!({})),
+            }
+        });
+        let dynamo = TokenBucket::new(mock.client("table"), settings(10, 10, 1, 60)).unwrap();
+
+        // Two intervals have passed, and write is clamped to max_tokens
+        assert_eq!(
+            dynamo.sub_buckets("a").await.unwrap(),
+            [("read".to_string(), 3), ("write".to_string(), 10)]
+        );
+        assert!(dynamo.sub_buckets("b").await.unwrap().is_empty());
+
+        // Backends that don't store sub-buckets have none
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+        bucket.limit("a", 1).await.unwrap();
+        assert!(bucket.sub_buckets("a").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn put_settings_never_overwrites_a_limit_row() {
+        let mock = MockDynamo::new(|_, body| {
+            // The row already written holds a limit, so the condition fails
+            if body["Item"]["pk"]["S"] == "limited" {
+                let error = serde_json::json!({
+                    "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                    "message": "The conditional request failed",
+                });
+                return (400, error);
+            }
+            (200, serde_json::json!({}))
+        });
+        let client = mock.client("table");
+
+        client
+            .put_settings("a", settings(10, 10, 1, 60))
+            .await
+            .unwrap();
+        let put = &mock.requests()[0].1;
+        assert_eq!(put["Item"]["sk"]["S"], "SETTINGS");
+        assert_eq!(
+            put["ConditionExpression"],
+            "attribute_not_exists(last_updated) AND attribute_not_exists(tokens)"
+        );
+        let result = client
+            .put_settings("limited", settings(10, 10, 1, 60))
+            .await;
+        assert!(matches!(result, Err(TokenBucketError::DynamoPut(_))));
+
+        // Names that collide with the keys or the LIMIT attributes are rejected before any write
+        let colliding = [
+            ("tokens", "sk"),
+            ("rl_max_tokens", "pk"),
+            ("last_updated", "sk"),
+        ];
+        for (max_tokens, sk_name) in colliding {
+            let mut client = mock.client("table");
+            client.settings_attributes.max_tokens = max_tokens.into();
+            client.sk_name = sk_name.into();
+            let result = client.put_settings("a", settings(10, 10, 1, 60)).await;
+            assert!(matches!(
+                result,
+                Err(TokenBucketError::InvalidSettingsAttributes(_))
+            ));
+        }
+        let mut client = mock.client("table");
+        client.settings_attributes.refill_rate = "sk".into();
+        assert!(client
+            .put_settings("a", settings(10, 10, 1, 60))
+            .await
+            .is_err());
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn on_consumed_reports_what_was_actually_spent() {
+        let (mut bucket, _) = bucket(settings(10, 10, 1, 60));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        bucket.on_consumed = Some(Box::new(move |units| {
+            let event = (units.key.clone(), units.tokens, units.refund);
+            recorded.lock().unwrap().push(event);
+        }));
+        let take = || std::mem::take(&mut *events.lock().unwrap());
+        let event = |key: &str, tokens, refund| (key.to_string(), tokens, refund);
+
+        bucket.limit("a", 3).await.unwrap();
+        bucket.limit("a", 20).await.unwrap();
+        bucket.refund("a", 1).await.unwrap();
+        assert_eq!(
+            take(),
+            [
+                event("a", 3, false),
+                event("a", 0, false),
+                event("a", 1, true)
+            ]
+        );
+
+        bucket
+            .reserve("b", 4)
+            .await
+            .unwrap()
+            .commit(6)
+            .await
+            .unwrap();
+        bucket
+            .reserve("b", 4)
+            .await
+            .unwrap()
+            .commit(1)
+            .await
+            .unwrap();
+        drop(bucket.reserve("b", 2).await.unwrap());
+        assert_eq!(
+            take(),
+            [
+                event("b", 4, false),
+                event("b", 2, false),
+                event("b", 4, false),
+                event("b", 3, true),
+                event("b", 2, false),
+                event("b", 2, true),
+            ]
+        );
+
+        let transaction = bucket.transaction().add("c", 1).add("d", 100);
+        assert!(!transaction.execute().await.unwrap().allowed);
+        assert_eq!(
+            take(),
+            [
+                event("c", 1, false),
+                event("d", 0, false),
+                event("c", 1, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn projection_covers_every_decoded_attribute() {
+        let mut client = MockDynamo::new(|_, _| (200, serde_json::json!({}))).client("table");
+        client.settings_attributes = SettingsAttributes::prefixed("rl_");
+
+        let limit = RateLimitItem {
+            last_updated: 1,
+            tokens: 1,
+            cap: Some(1),
+            fraction: 1,
+        };
+        let mut limit_row: HashMap<String, AttributeValue> = to_item(limit).unwrap();
+        schema::stamp(&mut limit_row);
+        let settings_row = client.settings_item("a", settings(1, 1, 1, 1)).unwrap();
+        let decoded: HashSet<&str> = limit_row
+            .keys()
+            .chain(settings_row.keys())
+            .map(String::as_str)
+            .collect();
+
+        let (projection, names) = client.projection();
+        let projected: HashSet<&str> = names.iter().map(|(_, attribute)| *attribute).collect();
+        assert_eq!(projected, decoded);
+        // Every placeholder is projected and given a name
+        let placeholders: Vec<&str> = projection.split(", ").collect();
+        assert_eq!(
+            placeholders,
+            names
+                .iter()
+                .map(|(placeholder, _)| placeholder.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn limit_at_replays_in_order() {
+        let (bucket, client) = bucket(settings(3, 3, 1, 60));
+        let t0 = 1_000_000;
+
+        let replayed = [
+            (t0, 2, LimitResult::Allow { remaining: 1 }, t0),
+            (t0 + 30, 1, LimitResult::Allow { remaining: 0 }, t0),
+            (t0 + 90, 1, LimitResult::Allow { remaining: 0 }, t0 + 60),
+            (t0 + 200, 2, LimitResult::Allow { remaining: 0 }, t0 + 180),
+        ];
+        for (at, cost, expected, last_updated) in replayed {
+            assert_eq!(bucket.limit_at("a", cost, at).await.unwrap(), expected);
+            let (limit, _) = client.get_raw("a").await.unwrap();
+            assert_eq!(limit.unwrap().last_updated, last_updated);
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_at_rejects_going_back_in_time() {
+        let (bucket, client) = bucket(settings(3, 3, 1, 60));
+        let t0 = 1_000_000;
+        bucket.limit_at("a", 1, t0 + 120).await.unwrap();
+
+        let earlier = bucket.limit_at("a", 1, t0).await;
+        assert!(matches!(
+            earlier,
+            Err(TokenBucketError::OutOfOrder { at, last_updated })
+                if at == t0 && last_updated == t0 + 120
+        ));
+        let (limit, _) = client.get_raw("a").await.unwrap();
+        assert_eq!(limit.unwrap().tokens, 2);
+    }
+
+    #[tokio::test]
+    async fn deleted_settings_clamp_to_the_default_max() {
+        let (bucket, client) = bucket(settings(5, 5, 1, 60));
+        let t0 = 1_000_000;
+        client
+            .put_settings("a", settings(20, 20, 1, 60))
+            .await
+            .unwrap();
+        assert_eq!(
+            bucket.limit_at("a", 2, t0).await.unwrap(),
+            LimitResult::Allow { remaining: 18 }
+        );
+
+        client.delete_settings("a");
+        // Clamped down to the default max_tokens before any interval has passed
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 30).await.unwrap(),
+            LimitResult::Allow { remaining: 4 }
+        );
+        let (limit, stored) = client.get_raw("a").await.unwrap();
+        assert_eq!(stored, None);
+        assert_eq!(limit.unwrap().last_updated, t0);
+    }
+
+    #[tokio::test]
+    async fn sub_interval_steps_refill_without_drift() {
+        let (bucket, _) = bucket(settings(10_000, 1_000, 7, 60));
+        let (t0, step, calls) = (1_000_000, 13, 1_000);
+
+        let mut remaining = 0;
+        for call in 1..=calls {
+            let result = bucket.limit_at("a", 1, t0 + call * step).await.unwrap();
+            let LimitResult::Allow { remaining: left } = result else {
+                panic!("call {call} was denied");
+            };
+            remaining = left;
+        }
+        // Every whole interval is credited once, however the calls fall within them
+        let refilled = calls * step / 60 * 7;
+        assert_eq!(remaining, 1_000 - calls + refilled);
+    }
+
+    #[tokio::test]
+    async fn fixed_point_accrues_within_an_interval() {
+        let (mut bucket, client) = bucket(settings(10, 1, 3, 4));
+        bucket.accounting = TokenAccounting::FixedPoint;
+        let t0 = 1_000_000;
+        let stored = || async { client.get_raw("a").await.unwrap().0.unwrap() };
+
+        bucket.limit_at("a", 1, t0).await.unwrap();
+        // 1.5 tokens two seconds in, where whole intervals would have added none yet
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 2).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+        let limit = stored().await;
+        assert_eq!((limit.fraction, limit.last_updated), (500_000, t0 + 2));
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 3).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+        // 0.25 held, the missing 0.75 accrues in one more second
+        assert!(matches!(
+            bucket.limit_at("a", 1, t0 + 3).await.unwrap(),
+            LimitResult::Deny { retry_after: 1, .. }
+        ));
+        assert_eq!(stored().await.fraction, 250_000);
+    }
+
+    #[tokio::test]
+    async fn fixed_point_refills_without_drift() {
+        let (mut bucket, client) = bucket(settings(u64::MAX, 0, 7, 8));
+        bucket.accounting = TokenAccounting::FixedPoint;
+        let t0 = 1_000_000;
+
+        for second in 0..=10_000 {
+            bucket.limit_at("a", 0, t0 + second).await.unwrap();
+        }
+        // 0.875 tokens a second, credited in full however many calls it's split across
+        let limit = client.get_raw("a").await.unwrap().0.unwrap();
+        assert_eq!((limit.tokens, limit.fraction), (8_750, 0));
+        bucket.limit_at("a", 0, t0 + 10_001).await.unwrap();
+        let limit = client.get_raw("a").await.unwrap().0.unwrap();
+        assert_eq!((limit.tokens, limit.fraction), (8_750, 875_000));
+    }
+
+    #[tokio::test]
+    async fn capped_refill_catches_up_across_denies() {
+        let (mut bucket, client) = bucket(settings(10, 10, 1, 60));
+        bucket.max_intervals_per_refill = Some(1);
+        let t0 = 1_000_000;
+        bucket.limit_at("a", 10, t0).await.unwrap();
+
+        // Idle for far longer than it takes to refill, but only one interval is applied per call
+        for call in 1..5 {
+            let at = t0 + call * 100_000;
+            assert_eq!(
+                bucket.limit_at("a", 5, at).await.unwrap(),
+                LimitResult::Deny {
+                    retry_after: 0,
+                    allowed_at: at,
+                    recoverable: true,
+                }
+            );
+            let (limit, _) = client.get_raw("a").await.unwrap();
+            assert_eq!(limit.unwrap().last_updated, t0 + call * 60);
+        }
+        assert_eq!(
+            bucket.limit_at("a", 5, t0 + 500_000).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn compare_and_put_settings_conditions_on_dynamo() {
+        let mock = MockDynamo::new(|_, body| {
+            if body["ConditionExpression"] == "attribute_not_exists(#key)" {
+                return (200, serde_json::json!({}));
+            }
+            let body = serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed",
+            });
+            (400, body)
+        });
+        let client = mock.client("table");
+        let (old, new) = (settings(1, 1, 1, 60), settings(2, 2, 1, 60));
+
+        // The mock only lets creates through, so the compare fails as a mismatch would
+        assert!(client
+            .compare_and_put_settings("a", None, new)
+            .await
+            .unwrap());
+        assert!(!client
+            .compare_and_put_settings("a", Some(old), new)
+            .await
+            .unwrap());
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].1["ExpressionAttributeNames"]["#key"], "pk");
+        let compare = &requests[1].1;
+        assert_eq!(
+            compare["ConditionExpression"],
+            "#a0 = :a0 AND (#a1 = :a1 OR (attribute_not_exists(#a1) AND #a0 = :a1)) AND #a2 = :a2 AND #a3 = :a3"
+        );
+        let compared: HashSet<(&str, &str)> = (0..4)
+            .map(|i| {
+                let attribute = &compare["ExpressionAttributeNames"][format!("#a{i}")];
+                let value = &compare["ExpressionAttributeValues"][format!(":a{i}")]["N"];
+                (attribute.as_str().unwrap(), value.as_str().unwrap())
+            })
+            .collect();
+        let stored = HashSet::from([
+            ("max_tokens", "1"),
+            ("starting_tokens", "1"),
+            ("refill_rate", "1"),
+            ("refill_interval", "60"),
+        ]);
+        assert_eq!(compared, stored);
+    }
+
+    #[tokio::test]
+    async fn inspect_shows_the_refill_before_clamping() {
+        let (bucket, client) = bucket(settings(10, 10, 2, 60));
+        // Five intervals ago with 8 tokens, so 10 accrue and all but 2 are clamped away
+        let last_updated = current_unix_time() - 5 * 60;
+        let limit = RateLimitItem {
+            last_updated,
+            tokens: 8,
+            cap: None,
+            fraction: 0,
+        };
+        client.put_limit("a", limit).await.unwrap();
+
+        let inspection = bucket.inspect("a").await.unwrap();
+        assert_eq!(
+            inspection,
+            BucketInspection {
+                stored_tokens: 8,
+                refilled_tokens: 10,
+                remaining: 10,
+                last_updated,
+                settings: settings(10, 10, 2, 60),
+            }
+        );
+        // Nothing was written
+        assert_eq!(client.get_raw("a").await.unwrap().0, Some(limit));
+    }
+
+    #[tokio::test]
+    async fn seed_policies_with_and_without_settings() {
+        let (mut bucket, client) = bucket(settings(10, 2, 1, 60));
+        client
+            .put_settings("configured", settings(20, 5, 1, 60))
+            .await
+            .unwrap();
+
+        let seeds = [
+            (SeedPolicy::StartingTokens, 2, 5),
+            (SeedPolicy::FullWhenConfigured, 2, 20),
+        ];
+        for (policy, default, configured) in seeds {
+            bucket.seed_policy = policy;
+            assert_eq!(bucket.peek("default").await.unwrap(), default, "{policy:?}");
+            assert_eq!(
+                bucket.peek("configured").await.unwrap(),
+                configured,
+                "{policy:?}"
+            );
+        }
+    }
+
+    /// Reads `stale` as the limit of every id, as if a newer write landed after the read
+    struct StaleReads {
+        inner: InMemoryClient,
+        stale: RateLimitItem,
+    }
+
+    impl TokenBucketClient for StaleReads {
+        type Error = TokenBucketError;
+
+        async fn get_raw(
+            &self,
+            id: &str,
+        ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+            let (_, settings) = self.inner.get_raw(id).await?;
+            Ok((Some(self.stale), settings))
+        }
+
+        async fn put_limit(
+            &self,
+            id: &str,
+            limit: RateLimitItem,
+        ) -> Result<PutOutcome, Self::Error> {
+            self.inner.put_limit(id, limit).await
+        }
+
+        async fn put_settings(
+            &self,
+            id: &str,
+            settings: RateLimitSettings,
+        ) -> Result<(), Self::Error> {
+            self.inner.put_settings(id, settings).await
+        }
+
+        async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+            self.inner.get_settings(id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_writes_that_lose_to_a_newer_one() {
+        let t0 = 1_000_000;
+        let newer = RateLimitItem {
+            last_updated: t0 + 60,
+            tokens: 3,
+            cap: None,
+            fraction: 0,
+        };
+        let inner = InMemoryClient::new();
+        inner.put_limit("a", newer).await.unwrap();
+        let stale = RateLimitItem {
+            last_updated: t0,
+            tokens: 10,
+            cap: None,
+            fraction: 0,
+        };
+        let client = StaleReads {
+            inner: inner.clone(),
+            stale,
+        };
+        let mut bucket = TokenBucket::new(client, settings(10, 10, 1, 60)).unwrap();
+        let contended = Arc::new(Mutex::new(Vec::new()));
+        let seen = contended.clone();
+        bucket.on_conflict = Some(Box::new(move |id| lock(&seen).push(id.to_string())));
+
+        assert_eq!(bucket.conflicts(), 0);
+        // Decided against the stale read, but the newer limit is kept
+        assert_eq!(
+            bucket.limit_at("a", 1, t0 + 30).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+        bucket.limit_at("b", 1, t0 + 30).await.unwrap();
+        assert_eq!(bucket.conflicts(), 1);
+        assert_eq!(*lock(&contended), ["a"]);
+        assert_eq!(inner.get_raw("a").await.unwrap().0, Some(newer));
+    }
+
+    #[tokio::test]
+    async fn quota_increases_top_up_or_grant_the_delta() {
+        let (mut bucket, client) = bucket(settings(10, 10, 1, 60));
+        let t0 = 1_000_000;
+
+        let increases = [
+            (QuotaIncrease::Ignore, 1),
+            (QuotaIncrease::TopUpToStarting, 5),
+            (QuotaIncrease::GrantDelta, 11),
+        ];
+        for (increase, remaining) in increases {
+            bucket.quota_increase = increase;
+            let id = format!("{increase:?}");
+            client
+                .put_settings(&id, settings(10, 6, 1, 60))
+                .await
+                .unwrap();
+            assert_eq!(
+                bucket.limit_at(&id, 4, t0).await.unwrap(),
+                LimitResult::Allow { remaining: 2 }
+            );
+
+            client
+                .put_settings(&id, settings(20, 6, 1, 60))
+                .await
+                .unwrap();
+            assert_eq!(
+                bucket.limit_at(&id, 1, t0 + 1).await.unwrap(),
+                LimitResult::Allow { remaining },
+                "{increase:?}"
+            );
+            // Only applied once, the raised cap is stored with the bucket
+            assert_eq!(
+                bucket.limit_at(&id, 1, t0 + 2).await.unwrap(),
+                LimitResult::Allow {
+                    remaining: remaining - 1
+                },
+                "{increase:?}"
+            );
+        }
+    }
+
+    /// A row with sort key `sk` in attribute `sk_name`, tagged with `tag` to tell rows apart
+    fn row(sk_name: &str, sk: AttributeValue, tag: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (sk_name.to_string(), sk),
+            ("tag".to_string(), AttributeValue::S(tag.into())),
+        ])
+    }
+
+    #[test]
+    fn bucket_rows_sorts_limit_and_settings() {
+        let sk = |sk: &str| AttributeValue::S(sk.into());
+        let items = vec![
+            row("sort", sk("LIMIT#burst"), "sub-bucket"),
+            row("sort", sk("SETTINGS"), "settings"),
+            row("sort", sk("LIMIT#DEAD"), "dead letter"),
+            row("sort", AttributeValue::N("1".into()), "numeric"),
+            row("other", sk("LIMIT"), "misnamed"),
+            row("sort", sk("LIMIT"), "limit"),
+        ];
+        let rows = BucketRows::from_items(items, "sort").unwrap();
+        assert_eq!(rows.limit, Some(row("sort", sk("LIMIT"), "limit")));
+        assert_eq!(rows.settings, Some(row("sort", sk("SETTINGS"), "settings")));
+
+        let rows = BucketRows::from_items(vec![], "sort").unwrap();
+        assert_eq!((rows.limit, rows.settings), (None, None));
+    }
+
+    #[test]
+    fn bucket_rows_rejects_duplicates() {
+        let sk = |sk: &str| AttributeValue::S(sk.into());
+        for duplicated in ["LIMIT", "SETTINGS"] {
+            let items = vec![
+                row("sk", sk(duplicated), "first"),
+                row("sk", sk("LIMIT#burst"), "sub-bucket"),
+                row("sk", sk(duplicated), "second"),
+            ];
+            let duplicate = BucketRows::from_items(items, "sk");
+            assert!(matches!(
+                duplicate,
+                Err(TokenBucketError::DuplicateRow(sk)) if sk == duplicated
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_timed_measures_the_backend() {
+        let client = FlakyClient::default();
+        let bucket = TokenBucket::new(client.clone(), settings(10, 10, 1, 60)).unwrap();
+        let (_, instant) = bucket.limit_timed("a", 1).await.unwrap();
+
+        client.delay_by(Duration::from_millis(5));
+        let (result, backend_time) = bucket.limit_timed("a", 1).await.unwrap();
+        assert_eq!(result, LimitResult::Allow { remaining: 8 });
+        // One read and one write, each delayed
+        assert!(backend_time >= Duration::from_millis(10));
+        assert!(backend_time > instant);
+    }
+
+    #[tokio::test]
+    async fn sharded_seeds_follow_the_logical_id() {
+        let (mut bucket, client) = bucket(settings(100, 40, 10, 60));
+        let sharding = Sharding::new(NonZeroU64::new(4).unwrap(), ShardStrategy::RoundRobin);
+        client
+            .put_settings("c", settings(100, 40, 10, 60))
+            .await
+            .unwrap();
+
+        assert_eq!(bucket.peek_sharded("d", &sharding).await.unwrap(), 40);
+        assert_eq!(bucket.peek_sharded("c", &sharding).await.unwrap(), 40);
+
+        // Only a logical id with settings of its own starts full, as it would unsharded
+        bucket.seed_policy = SeedPolicy::FullWhenConfigured;
+        assert_eq!(bucket.peek("d").await.unwrap(), 40);
+        assert_eq!(bucket.peek_sharded("d", &sharding).await.unwrap(), 40);
+        assert_eq!(bucket.peek_sharded("c", &sharding).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn sharded_ids_up_to_the_max_length() {
+        let (mut bucket, _) = bucket(settings(100, 100, 10, 60));
+        bucket.max_key_length = 8;
+        let sharding = Sharding::new(NonZeroU64::new(4).unwrap(), ShardStrategy::RoundRobin);
+
+        let longest = bucket
+            .limit_sharded("01234567", 5, &sharding)
+            .await
+            .unwrap();
+        assert_eq!(longest, LimitResult::Allow { remaining: 20 });
+        assert_eq!(
+            bucket.peek_sharded("01234567", &sharding).await.unwrap(),
+            95
+        );
+
+        let too_long = bucket.limit_sharded("012345678", 5, &sharding).await;
+        assert!(matches!(too_long, Err(TokenBucketError::InvalidKey(_))));
+        let too_long = bucket.peek_sharded("012345678", &sharding).await;
+        assert!(matches!(too_long, Err(TokenBucketError::InvalidKey(_))));
+    }
+
+    #[tokio::test]
+    async fn peek_sharded_sums_every_shard() {
+        let (bucket, client) = bucket(settings(100, 100, 10, 60));
+        let sharding = Sharding::new(NonZeroU64::new(4).unwrap(), ShardStrategy::RoundRobin);
+
+        for _ in 0..6 {
+            let allowed = bucket.limit_sharded("a", 5, &sharding).await.unwrap();
+            assert!(matches!(allowed, LimitResult::Allow { .. }));
+        }
+        assert_eq!(bucket.peek_sharded("a", &sharding).await.unwrap(), 70);
+        // Each shard holds its own quarter, the first two spent from twice
+        let mut shards = Vec::new();
+        for n in 0..4 {
+            let (limit, _) = client.get_raw(&sharding.shard_key("a", n)).await.unwrap();
+            shards.push(limit.unwrap().tokens);
+        }
+        assert_eq!(shards, [15, 15, 20, 20]);
+    }
+
+    #[tokio::test]
+    async fn backend_timeout_bounds_slow_calls() {
+        let mock = MockDynamo::new(|_, _| (200, serde_json::json!({"Items": []})))
+            .delayed(Duration::from_millis(200));
+        let timeout = Duration::from_millis(10);
+
+        let client = mock
+            .client_with_sleep("table", TokioSleep)
+            .with_backend_timeout(timeout)
+            .unwrap();
+        let timed_out = client.get_raw("a").await;
+        assert!(matches!(timed_out, Err(TokenBucketError::Timeout(t)) if t == timeout));
+
+        let client = mock
+            .client_with_sleep("table", TokioSleep)
+            .with_backend_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(client.get_raw("a").await.unwrap(), (None, None));
+
+        // Without a sleep there's nothing to time calls with
+        let untimed = mock.client("table").with_backend_timeout(timeout);
+        assert!(matches!(untimed, Err(TokenBucketError::NoSleepImpl)));
+    }
+
+    #[tokio::test]
+    async fn unaffordable_costs_are_unrecoverable() {
+        let (bucket, client) = bucket(settings(10, 10, 1, 60));
+        let never = LimitResult::Deny {
+            retry_after: u64::MAX,
+            allowed_at: u64::MAX,
+            recoverable: false,
+        };
+        let t0 = 1_000_000;
+
+        // More than the bucket can ever hold
+        assert_eq!(bucket.limit_at("a", 11, t0).await.unwrap(), never);
+
+        // Never refilled, so once spent it stays spent
+        client
+            .put_settings("b", settings(10, 10, 0, 60))
+            .await
+            .unwrap();
+        assert_eq!(
+            bucket.limit_at("b", 10, t0).await.unwrap(),
+            LimitResult::Allow { remaining: 0 }
+        );
+        assert_eq!(bucket.limit_at("b", 1, t0 + 600).await.unwrap(), never);
+    }
+
+    #[tokio::test]
+    async fn over_max_policies_clamp_or_keep_the_excess() {
+        let (mut bucket, client) = bucket(settings(10, 10, 1, 60));
+        let t0 = 1_000_000;
+        let over = RateLimitItem {
+            last_updated: t0,
+            tokens: 15,
+            cap: None,
+            fraction: 0,
+        };
+
+        client.put_limit("clamped", over).await.unwrap();
+        assert_eq!(
+            bucket.limit_at("clamped", 1, t0 + 1).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+
+        bucket.over_max = OverMaxPolicy::DecayBySpending;
+        client.put_limit("kept", over).await.unwrap();
+        assert_eq!(
+            bucket.limit_at("kept", 1, t0 + 1).await.unwrap(),
+            LimitResult::Allow { remaining: 14 }
+        );
+        // Nothing refills while over max_tokens
+        assert_eq!(
+            bucket.limit_at("kept", 5, t0 + 120).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+        // Back under it, refill picks up from the intervals already used
+        assert_eq!(
+            bucket.limit_at("kept", 1, t0 + 180).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+    }
 }
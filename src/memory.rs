@@ -0,0 +1,161 @@
+use crate::{
+    lock, PutOutcome, RateLimitItem, RateLimitSettings, TokenBucketClient, TokenBucketError,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Clone, Default)]
+/// In-process storage for the token bucket
+/// Limits are shared between clones of the same client but not between processes
+pub struct InMemoryClient {
+    rows: Arc<Mutex<HashMap<String, Rows>>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// The LIMIT and SETTINGS rows stored for an id
+struct Rows {
+    limit: Option<RateLimitItem>,
+    settings: Option<RateLimitSettings>,
+}
+
+impl InMemoryClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn rows(&self) -> MutexGuard<'_, HashMap<String, Rows>> {
+        lock(&self.rows)
+    }
+
+    #[cfg(test)]
+    /// Remove the SETTINGS row of `id`, as if it had been deleted by hand
+    pub(crate) fn delete_settings(&self, id: &str) {
+        if let Some(rows) = self.rows().get_mut(id) {
+            rows.settings = None;
+        }
+    }
+}
+
+impl TokenBucketClient for InMemoryClient {
+    type Error = TokenBucketError;
+
+    async fn get_raw(
+        &self,
+        id: &str,
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        let rows = self.rows().get(id).copied().unwrap_or_default();
+        Ok((rows.limit, rows.settings))
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
+        let mut rows = self.rows();
+        let stored = &mut rows.entry(id.into()).or_default().limit;
+        // Same rule as the DynamoDB condition, never overwrite a newer limit
+        match *stored {
+            Some(current) if current.last_updated > limit.last_updated => {
+                Ok(PutOutcome::Conflict {
+                    current: Some(current),
+                })
+            }
+            _ => {
+                *stored = Some(limit);
+                Ok(PutOutcome::Written)
+            }
+        }
+    }
+
+    async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
+        self.rows().entry(id.into()).or_default().settings = Some(settings);
+        Ok(())
+    }
+
+    async fn compare_and_put_settings(
+        &self,
+        id: &str,
+        expected: Option<RateLimitSettings>,
+        new: RateLimitSettings,
+    ) -> Result<bool, Self::Error> {
+        let mut rows = self.rows();
+        let stored = &mut rows.entry(id.into()).or_default().settings;
+        if *stored != expected {
+            return Ok(false);
+        }
+        *stored = Some(new);
+        Ok(true)
+    }
+
+    async fn refund(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        if let Some(limit) = self.rows().get_mut(id).and_then(|rows| rows.limit.as_mut()) {
+            limit.tokens = limit.tokens.saturating_add(tokens);
+        }
+        Ok(())
+    }
+
+    async fn spend(&self, id: &str, tokens: u64) -> Result<(), Self::Error> {
+        if let Some(limit) = self.rows().get_mut(id).and_then(|rows| rows.limit.as_mut()) {
+            limit.tokens = limit.tokens.saturating_sub(tokens);
+        }
+        Ok(())
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        Ok(self.rows().get(id).and_then(|rows| rows.settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::full_settings;
+
+    #[tokio::test]
+    async fn get_settings_many_keeps_input_order() {
+        let client = InMemoryClient::new();
+        client.put_settings("a", full_settings(1)).await.unwrap();
+        client.put_settings("c", full_settings(3)).await.unwrap();
+
+        let many = client
+            .get_settings_many(&["c", "b", "a", "c"])
+            .await
+            .unwrap();
+        assert_eq!(
+            many,
+            [
+                Some(full_settings(3)),
+                None,
+                Some(full_settings(1)),
+                Some(full_settings(3))
+            ]
+        );
+        assert!(client.get_settings_many(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn compare_and_put_settings_only_replaces_the_expected() {
+        let client = InMemoryClient::new();
+        let (one, two) = (full_settings(1), full_settings(2));
+
+        // Creating needs the id to have no settings yet
+        assert!(client
+            .compare_and_put_settings("a", None, one)
+            .await
+            .unwrap());
+        assert!(!client
+            .compare_and_put_settings("a", None, two)
+            .await
+            .unwrap());
+        assert!(!client
+            .compare_and_put_settings("a", Some(two), two)
+            .await
+            .unwrap());
+        assert_eq!(client.get_settings("a").await.unwrap(), Some(one));
+
+        assert!(client
+            .compare_and_put_settings("a", Some(one), two)
+            .await
+            .unwrap());
+        assert_eq!(client.get_settings("a").await.unwrap(), Some(two));
+    }
+}
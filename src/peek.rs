@@ -0,0 +1,111 @@
+use crate::{lock, RefillPolicy, TokenBucket, TokenBucketClient, TokenBucketError};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// The default for `CachedPeekClient::max_age`
+pub const DEFAULT_PEEK_MAX_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The tokens a key holds, as returned by `CachedPeekClient::peek`
+pub enum PeekResult {
+    /// Read from the backend just now
+    Live { remaining: u64 },
+    /// The backend read failed, this is the last value read `age` ago
+    /// The key may have been spent from or refilled since
+    Stale { remaining: u64, age: Duration },
+}
+
+impl PeekResult {
+    pub fn remaining(&self) -> u64 {
+        match self {
+            PeekResult::Live { remaining } | PeekResult::Stale { remaining, .. } => *remaining,
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        matches!(self, PeekResult::Stale { .. })
+    }
+}
+
+/// Peeks at buckets for display, falling back to the last value read when the backend fails
+/// Meant for dashboards that should keep working through backend errors, not for limiting
+pub struct CachedPeekClient<T: TokenBucketClient, R: RefillPolicy> {
+    pub bucket: Arc<TokenBucket<T, R>>,
+    /// How long a value is served after the backend starts failing, `DEFAULT_PEEK_MAX_AGE` by default
+    pub max_age: Duration,
+    cache: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl<T: TokenBucketClient, R: RefillPolicy> CachedPeekClient<T, R>
+where
+    T::Error: From<TokenBucketError>,
+{
+    pub fn new(bucket: Arc<TokenBucket<T, R>>) -> Self {
+        Self {
+            bucket,
+            max_age: DEFAULT_PEEK_MAX_AGE,
+            cache: Mutex::default(),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<String, (u64, Instant)>> {
+        lock(&self.cache)
+    }
+
+    /// The tokens `id` holds, see `TokenBucket::peek`
+    /// If the read fails and `id` was read successfully within `max_age`, that value is
+    /// returned as `PeekResult::Stale` instead of the error
+    pub async fn peek(&self, id: &str) -> Result<PeekResult, T::Error> {
+        let error = match self.bucket.peek(id).await {
+            Ok(remaining) => {
+                self.cache().insert(id.into(), (remaining, Instant::now()));
+                return Ok(PeekResult::Live { remaining });
+            }
+            Err(error) => error,
+        };
+
+        let cached = self.cache().get(id).copied();
+        match cached {
+            Some((remaining, read_at)) if read_at.elapsed() <= self.max_age => {
+                Ok(PeekResult::Stale {
+                    remaining,
+                    age: read_at.elapsed(),
+                })
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{settings, FlakyClient};
+
+    #[tokio::test]
+    async fn serves_the_last_read_until_max_age() {
+        let backend = FlakyClient::default();
+        let bucket = TokenBucket::new(backend.clone(), settings(10, 10, 1, 60)).unwrap();
+        bucket.limit("a", 3).await.unwrap();
+        let mut peek = CachedPeekClient::new(Arc::new(bucket));
+
+        assert_eq!(
+            peek.peek("a").await.unwrap(),
+            PeekResult::Live { remaining: 7 }
+        );
+        backend.fail_with(|| TokenBucketError::Timeout(Duration::from_secs(1)));
+        let stale = peek.peek("a").await.unwrap();
+        assert!(stale.is_stale());
+        assert_eq!(stale.remaining(), 7);
+        // Never read, so there's nothing to fall back to
+        assert!(peek.peek("b").await.is_err());
+
+        std::thread::sleep(Duration::from_millis(5));
+        peek.max_age = Duration::from_millis(1);
+        let expired = peek.peek("a").await;
+        assert!(matches!(expired, Err(TokenBucketError::Timeout(_))));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{RateLimitItem, RateLimitSettings};
+use std::cmp;
+
+/// Decides how many tokens a bucket holds after time has passed
+/// `TokenBucket` advances `last_updated` by whole `refill_interval`s after every refill,
+/// so policies should measure elapsed time in intervals as well (see `elapsed_intervals`).
+/// Under `TokenAccounting::FixedPoint` the bucket refills linearly itself and no policy is used.
+/// The `retry_after` reported on a deny assumes linear refill, so it's an estimate for other policies
+pub trait RefillPolicy {
+    /// The number of tokens `item` holds at `now`, given the resolved `settings`
+    fn refill(&self, item: &RateLimitItem, settings: &RateLimitSettings, now: u64) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+/// Adds `refill_rate` tokens every `refill_interval`, up to `max_tokens`
+/// A bucket already over `max_tokens`, e.g. after its settings changed to a lower limit,
+/// is clamped to it on the next refill whether or not an interval has passed
+pub struct LinearRefill;
+
+impl RefillPolicy for LinearRefill {
+    fn refill(&self, item: &RateLimitItem, settings: &RateLimitSettings, now: u64) -> u64 {
+        let refilled_tokens =
+            elapsed_intervals(item, settings, now).saturating_mul(settings.refill_rate);
+        cmp::min(
+            settings.max_tokens,
+            item.tokens.saturating_add(refilled_tokens),
+        )
+    }
+}
+
+/// The number of whole `refill_interval`s between `item.last_updated` and `now`
+pub fn elapsed_intervals(item: &RateLimitItem, settings: &RateLimitSettings, now: u64) -> u64 {
+    // refill_interval is a NonZeroU64 so this can't divide by zero
+    now.saturating_sub(item.last_updated) / settings.refill_interval.get()
+}
+
+#[cfg(feature = "decay")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Moves the tokens towards `baseline` by `refill_rate` every `refill_interval`, from either side
+/// Unlike `LinearRefill` a bucket holding more than `baseline`, e.g. after a grant, drifts back down.
+/// The tokens aren't capped at `max_tokens`, anything above it decays like any other surplus
+pub struct DecayPolicy {
+    pub baseline: u64,
+}
+
+#[cfg(feature = "decay")]
+impl RefillPolicy for DecayPolicy {
+    fn refill(&self, item: &RateLimitItem, settings: &RateLimitSettings, now: u64) -> u64 {
+        let step = elapsed_intervals(item, settings, now).saturating_mul(settings.refill_rate);
+        match item.tokens.cmp(&self.baseline) {
+            cmp::Ordering::Less => cmp::min(self.baseline, item.tokens.saturating_add(step)),
+            cmp::Ordering::Greater => cmp::max(self.baseline, item.tokens.saturating_sub(step)),
+            cmp::Ordering::Equal => item.tokens,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "decay"))]
+mod tests {
+    use super::*;
+    use crate::test_support::settings;
+
+    fn item(tokens: u64) -> RateLimitItem {
+        RateLimitItem {
+            last_updated: 1_000,
+            tokens,
+            cap: None,
+            fraction: 0,
+        }
+    }
+
+    #[test]
+    fn decays_down_to_the_baseline() {
+        let policy = DecayPolicy { baseline: 10 };
+        let settings = settings(20, 20, 3, 60);
+
+        assert_eq!(policy.refill(&item(20), &settings, 1_059), 20);
+        assert_eq!(policy.refill(&item(20), &settings, 1_120), 14);
+        // Never overshoots the baseline
+        assert_eq!(policy.refill(&item(20), &settings, 1_300), 10);
+        // Above max_tokens isn't clamped, it decays like any other surplus
+        assert_eq!(policy.refill(&item(30), &settings, 1_060), 27);
+    }
+
+    #[test]
+    fn refills_up_to_the_baseline() {
+        let policy = DecayPolicy { baseline: 10 };
+        let settings = settings(20, 20, 3, 60);
+
+        assert_eq!(policy.refill(&item(2), &settings, 1_120), 8);
+        assert_eq!(policy.refill(&item(2), &settings, 1_300), 10);
+        assert_eq!(policy.refill(&item(10), &settings, 1_300), 10);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{LimitResult, RefillPolicy, TokenBucket, TokenBucketClient, TokenBucketError};
+use std::cmp::Ordering;
+
+#[must_use = "dropping a reservation cancels it"]
+/// Tokens spent up front by `TokenBucket::reserve`, to be settled once the actual cost is known
+///
+/// Dropping a reservation without settling it cancels it. `Drop` can't talk to the backend,
+/// so the refund is queued on the bucket and applied the next time the id is limited or refunded.
+pub struct Reservation<'a, T: TokenBucketClient, R: RefillPolicy> {
+    bucket: &'a TokenBucket<T, R>,
+    id: String,
+    estimate: u64,
+    result: LimitResult,
+    settled: bool,
+}
+
+impl<'a, T: TokenBucketClient, R: RefillPolicy> Reservation<'a, T, R>
+where
+    T::Error: From<TokenBucketError>,
+{
+    pub(crate) fn new(
+        bucket: &'a TokenBucket<T, R>,
+        id: &str,
+        estimate: u64,
+        result: LimitResult,
+    ) -> Self {
+        Self {
+            bucket,
+            id: id.into(),
+            estimate,
+            result,
+            settled: false,
+        }
+    }
+
+    /// The result of spending the estimate
+    /// A denied reservation holds no tokens, settling it does nothing
+    pub fn result(&self) -> LimitResult {
+        self.result
+    }
+
+    pub fn is_allowed(&self) -> bool {
+        matches!(self.result, LimitResult::Allow { .. })
+    }
+
+    /// Settle at the `actual` cost, refunding what was over-reserved or spending what was missing
+    /// Extra spending can't be denied since the work already happened, it drains the bucket instead
+    pub async fn commit(mut self, actual: u64) -> Result<(), T::Error> {
+        self.settled = true;
+        if !self.is_allowed() {
+            return Ok(());
+        }
+        match actual.cmp(&self.estimate) {
+            Ordering::Less => self.bucket.refund(&self.id, self.estimate - actual).await,
+            Ordering::Greater => {
+                self.bucket
+                    .force_spend(&self.id, actual - self.estimate)
+                    .await
+            }
+            Ordering::Equal => Ok(()),
+        }
+    }
+
+    /// Give the whole estimate back
+    pub async fn cancel(mut self) -> Result<(), T::Error> {
+        self.settled = true;
+        if !self.is_allowed() {
+            return Ok(());
+        }
+        self.bucket.refund(&self.id, self.estimate).await
+    }
+}
+
+impl<T: TokenBucketClient, R: RefillPolicy> Drop for Reservation<'_, T, R> {
+    fn drop(&mut self) {
+        if !self.settled && matches!(self.result, LimitResult::Allow { .. }) {
+            self.bucket.queue_refund(&self.id, self.estimate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_support::{bucket, settings},
+        LimitResult,
+    };
+
+    #[tokio::test]
+    async fn commit_refunds_or_spends_the_difference() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+
+        let reservation = bucket.reserve("under", 4).await.unwrap();
+        assert_eq!(reservation.result(), LimitResult::Allow { remaining: 6 });
+        reservation.commit(1).await.unwrap();
+        assert_eq!(bucket.peek("under").await.unwrap(), 9);
+
+        let reservation = bucket.reserve("over", 4).await.unwrap();
+        reservation.commit(7).await.unwrap();
+        assert_eq!(bucket.peek("over").await.unwrap(), 3);
+
+        // The work already happened, so spending past empty drains the bucket instead of failing
+        let reservation = bucket.reserve("drained", 4).await.unwrap();
+        reservation.commit(20).await.unwrap();
+        assert_eq!(bucket.peek("drained").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_and_drop_give_the_estimate_back() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+
+        let reservation = bucket.reserve("cancelled", 4).await.unwrap();
+        reservation.cancel().await.unwrap();
+        assert_eq!(bucket.peek("cancelled").await.unwrap(), 10);
+
+        drop(bucket.reserve("dropped", 4).await.unwrap());
+        // The refund is queued until the id is next limited
+        assert_eq!(bucket.peek("dropped").await.unwrap(), 6);
+        assert_eq!(
+            bucket.limit("dropped", 1).await.unwrap(),
+            LimitResult::Allow { remaining: 9 }
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_reservations_hold_nothing() {
+        let (bucket, _) = bucket(settings(10, 2, 1, 60));
+
+        let reservation = bucket.reserve("a", 4).await.unwrap();
+        assert!(!reservation.is_allowed());
+        reservation.commit(4).await.unwrap();
+        assert_eq!(bucket.peek("a").await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,207 @@
+//! The `schema_version` attribute `TokenDynamoClient` stores on LIMIT and SETTINGS rows
+//!
+//! Rows are written with `SCHEMA_VERSION` and migrated to it when read. Rows without the
+//! attribute are version 1, written before it existed.
+//! - 1 to 2: SETTINGS rows may lack `starting_tokens`, those buckets start full at `max_tokens`.
+//!   LIMIT rows kept their shape
+//! - 2 to 3: LIMIT rows may carry `fraction`, the millionths of a token accrued under
+//!   `TokenAccounting::FixedPoint`. Older rows hold whole tokens, a fraction of 0
+
+use crate::TokenBucketError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::de::Error as _;
+use std::collections::HashMap;
+
+/// The schema version rows are written with
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// The attribute the schema version is stored under
+pub(crate) const SCHEMA_VERSION_ATTRIBUTE: &str = "schema_version";
+
+type Item = HashMap<String, AttributeValue>;
+
+/// Record the current schema version on a row about to be written
+pub(crate) fn stamp(item: &mut Item) {
+    item.insert(
+        SCHEMA_VERSION_ATTRIBUTE.into(),
+        AttributeValue::N(SCHEMA_VERSION.to_string()),
+    );
+}
+
+/// Fail for a row written by newer code, which this version can't know how to read
+/// A row like that must not be treated as corrupt, or it would be reset
+pub(crate) fn check_supported(item: &Item) -> Result<(), TokenBucketError> {
+    match version(item) {
+        Ok(version) if version > SCHEMA_VERSION => Err(TokenBucketError::SchemaVersion(version)),
+        _ => Ok(()),
+    }
+}
+
+/// Bring a LIMIT row to the current schema, without its version attribute
+pub(crate) fn migrate_limit(mut item: Item) -> Result<Item, serde_dynamo::Error> {
+    if version(&item)? < 3 {
+        item.entry("fraction".into())
+            .or_insert_with(|| AttributeValue::N("0".into()));
+    }
+    item.remove(SCHEMA_VERSION_ATTRIBUTE);
+    Ok(item)
+}
+
+/// Bring a SETTINGS row, renamed to its field names, to the current schema
+pub(crate) fn migrate_settings(mut item: Item) -> Result<Item, serde_dynamo::Error> {
+    if version(&item)? < 2 && !item.contains_key("starting_tokens") {
+        if let Some(max_tokens) = item.get("max_tokens").cloned() {
+            item.insert("starting_tokens".into(), max_tokens);
+        }
+    }
+    item.remove(SCHEMA_VERSION_ATTRIBUTE);
+    Ok(item)
+}
+
+/// The version a row was written with, an unreadable version makes the row corrupt
+fn version(item: &Item) -> Result<u32, serde_dynamo::Error> {
+    match item.get(SCHEMA_VERSION_ATTRIBUTE) {
+        None => Ok(1),
+        Some(AttributeValue::N(version)) => version
+            .parse()
+            .map_err(|_| serde_dynamo::Error::custom("invalid schema_version")),
+        Some(_) => Err(serde_dynamo::Error::custom("schema_version isn't a number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_support::{settings, MockDynamo},
+        RateLimitItem, TokenBucketClient, TokenDynamoClient,
+    };
+    use serde_json::{json, Value};
+
+    /// A client whose table holds `rows` in every partition
+    fn client(rows: Value) -> TokenDynamoClient {
+        MockDynamo::new(move |_, _| (200, json!({ "Items": rows }))).client("table")
+    }
+
+    #[tokio::test]
+    async fn reads_v1_settings_without_starting_tokens() {
+        let v1 = json!([{
+            "pk": {"S": "a"},
+            "sk": {"S": "SETTINGS"},
+            "max_tokens": {"N": "10"},
+            "refill_rate": {"N": "1"},
+            "refill_interval": {"N": "60"},
+        }]);
+        let (_, stored) = client(v1).get_raw("a").await.unwrap();
+        assert_eq!(stored, Some(settings(10, 10, 1, 60)));
+    }
+
+    #[tokio::test]
+    async fn rejects_rows_from_a_newer_schema() {
+        let newer = json!([{
+            "pk": {"S": "a"},
+            "sk": {"S": "LIMIT"},
+            "tokens": {"N": "1"},
+            "last_updated": {"N": "1"},
+            "schema_version": {"N": (SCHEMA_VERSION + 1).to_string()},
+        }]);
+        let read = client(newer).get_raw("a").await;
+        assert!(matches!(
+            read,
+            Err(TokenBucketError::SchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_limits_as_whole_tokens() {
+        let limit = |fraction| RateLimitItem {
+            last_updated: 1,
+            tokens: 3,
+            cap: None,
+            fraction,
+        };
+        let v2 = json!([{
+            "pk": {"S": "a"},
+            "sk": {"S": "LIMIT"},
+            "tokens": {"N": "3"},
+            "last_updated": {"N": "1"},
+            "schema_version": {"N": "2"},
+        }]);
+        let (stored, _) = client(v2).get_raw("a").await.unwrap();
+        assert_eq!(stored, Some(limit(0)));
+
+        let v3 = json!([{
+            "pk": {"S": "a"},
+            "sk": {"S": "LIMIT"},
+            "tokens": {"N": "3"},
+            "last_updated": {"N": "1"},
+            "fraction": {"N": "250000"},
+            "schema_version": {"N": "3"},
+        }]);
+        let (stored, _) = client(v3).get_raw("a").await.unwrap();
+        assert_eq!(stored, Some(limit(250_000)));
+    }
+
+    #[tokio::test]
+    async fn compares_settings_against_v1_rows() {
+        let mock = MockDynamo::new(|operation, _| match operation {
+            "Query" => (
+                200,
+                json!({ "Items": [{
+                    "pk": {"S": "a"},
+                    "sk": {"S": "SETTINGS"},
+                    "max_tokens": {"N": "10"},
+                    "refill_rate": {"N": "1"},
+                    "refill_interval": {"N": "60"},
+                }]}),
+            ),
+            _ => (200, json!({})),
+        });
+        let client = mock.client("table");
+        let (_, stored) = client.get_raw("a").await.unwrap();
+        assert!(client
+            .compare_and_put_settings("a", stored, settings(20, 20, 1, 60))
+            .await
+            .unwrap());
+
+        // The row has no starting_tokens to compare, so max_tokens has to hold the expected value
+        let put = &mock.requests()[1].1;
+        let condition = put["ConditionExpression"].as_str().unwrap();
+        assert!(condition.contains("(#a1 = :a1 OR (attribute_not_exists(#a1) AND #a0 = :a1))"));
+        assert_eq!(put["ExpressionAttributeNames"]["#a0"], "max_tokens");
+        assert_eq!(put["ExpressionAttributeNames"]["#a1"], "starting_tokens");
+        assert_eq!(put["ExpressionAttributeValues"][":a1"], json!({"N": "10"}));
+    }
+
+    #[tokio::test]
+    async fn conflicts_with_rows_from_a_newer_schema_fail() {
+        let mock = MockDynamo::new(|_, _| {
+            let body = json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed",
+                "Item": {
+                    "pk": {"S": "a"},
+                    "sk": {"S": "LIMIT"},
+                    "tokens": {"N": "1"},
+                    "last_updated": {"N": "2"},
+                    "schema_version": {"N": (SCHEMA_VERSION + 1).to_string()},
+                },
+            });
+            (400, body)
+        });
+        let mut client = mock.client("table");
+        client.return_conflicting_item = true;
+        let limit = RateLimitItem {
+            last_updated: 1,
+            tokens: 1,
+            cap: None,
+            fraction: 0,
+        };
+
+        let put = client.put_limit("a", limit).await;
+        assert!(matches!(
+            put,
+            Err(TokenBucketError::SchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+}
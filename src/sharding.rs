@@ -0,0 +1,49 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    num::NonZeroU64,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+/// How `TokenBucket::limit_sharded` picks the shard a call spends from
+pub enum ShardStrategy {
+    /// A random shard for every call
+    #[default]
+    Random,
+    /// Every shard in turn, per `Sharding`
+    RoundRobin,
+}
+
+#[derive(Debug)]
+/// Splits one logical limit across `shards` keys, see `TokenBucket::limit_sharded`
+pub struct Sharding {
+    pub shards: NonZeroU64,
+    pub strategy: ShardStrategy,
+    next: AtomicU64,
+}
+
+impl Sharding {
+    pub fn new(shards: NonZeroU64, strategy: ShardStrategy) -> Self {
+        Self {
+            shards,
+            strategy,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// The shard the next call should spend from
+    pub(crate) fn pick(&self) -> u64 {
+        let n = match self.strategy {
+            // Every RandomState is seeded differently, which is random enough to spread load
+            ShardStrategy::Random => RandomState::new().build_hasher().finish(),
+            ShardStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        n % self.shards.get()
+    }
+
+    /// The key shard `n` of `id` is stored under
+    pub fn shard_key(&self, id: &str, n: u64) -> String {
+        format!("{id}#shard{n}")
+    }
+}
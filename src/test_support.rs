@@ -0,0 +1,230 @@
+//! Fixtures shared by the unit tests
+
+use crate::{
+    lock, InMemoryClient, PutOutcome, RateLimitItem, RateLimitSettings, TokenBucket,
+    TokenBucketClient, TokenBucketError, TokenDynamoClient,
+};
+use aws_sdk_dynamodb::{
+    config::{self, retry::RetryConfig, AsyncSleep, BehaviorVersion, Credentials, Region, Sleep},
+    Client, Config,
+};
+use aws_smithy_runtime_api::{
+    client::{
+        http::{
+            HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings,
+            SharedHttpConnector,
+        },
+        orchestrator::{HttpRequest, HttpResponse},
+        runtime_components::RuntimeComponents,
+    },
+    http::StatusCode,
+};
+use aws_smithy_types::body::SdkBody;
+use serde_json::Value;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub(crate) fn settings(
+    max_tokens: u64,
+    starting_tokens: u64,
+    refill_rate: u64,
+    refill_interval: u64,
+) -> RateLimitSettings {
+    RateLimitSettings::new(max_tokens, starting_tokens, refill_rate, refill_interval).unwrap()
+}
+
+/// Settings starting full, refilling one token a minute
+pub(crate) fn full_settings(max_tokens: u64) -> RateLimitSettings {
+    settings(max_tokens, max_tokens, 1, 60)
+}
+
+/// A bucket over a fresh `InMemoryClient`, along with a clone sharing its rows
+pub(crate) fn bucket(
+    default_settings: RateLimitSettings,
+) -> (TokenBucket<InMemoryClient>, InMemoryClient) {
+    let client = InMemoryClient::new();
+    let bucket = TokenBucket::new(client.clone(), default_settings).unwrap();
+    (bucket, client)
+}
+
+#[derive(Debug)]
+/// `AsyncSleep` on the tokio timer
+pub(crate) struct TokioSleep;
+
+impl AsyncSleep for TokioSleep {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep::new(tokio::time::sleep(duration))
+    }
+}
+
+/// Makes the error a `FlakyClient` fails with
+type Failure = fn() -> TokenBucketError;
+
+#[derive(Debug, Clone, Default)]
+/// An `InMemoryClient` that can be made to fail or slow down, standing in for a backend having
+/// an outage. Clones share their rows, failure and delay
+pub(crate) struct FlakyClient {
+    pub inner: InMemoryClient,
+    failure: Arc<Mutex<Option<Failure>>>,
+    delay: Arc<Mutex<Duration>>,
+}
+
+impl FlakyClient {
+    /// Fail every call with the error `failure` makes until `recover` is called
+    pub fn fail_with(&self, failure: Failure) {
+        *lock(&self.failure) = Some(failure);
+    }
+
+    pub fn recover(&self) {
+        *lock(&self.failure) = None;
+    }
+
+    /// Wait `delay` before answering every call
+    pub fn delay_by(&self, delay: Duration) {
+        *lock(&self.delay) = delay;
+    }
+
+    async fn check(&self) -> Result<(), TokenBucketError> {
+        let delay = *lock(&self.delay);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        match *lock(&self.failure) {
+            Some(failure) => Err(failure()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TokenBucketClient for FlakyClient {
+    type Error = TokenBucketError;
+
+    async fn get_raw(
+        &self,
+        id: &str,
+    ) -> Result<(Option<RateLimitItem>, Option<RateLimitSettings>), Self::Error> {
+        self.check().await?;
+        self.inner.get_raw(id).await
+    }
+
+    async fn put_limit(&self, id: &str, limit: RateLimitItem) -> Result<PutOutcome, Self::Error> {
+        self.check().await?;
+        self.inner.put_limit(id, limit).await
+    }
+
+    async fn put_settings(&self, id: &str, settings: RateLimitSettings) -> Result<(), Self::Error> {
+        self.check().await?;
+        self.inner.put_settings(id, settings).await
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<RateLimitSettings>, Self::Error> {
+        self.check().await?;
+        self.inner.get_settings(id).await
+    }
+}
+
+/// Answers a `MockDynamo` request from its operation, e.g. `Query`, and JSON body
+type Respond = dyn Fn(&str, &Value) -> (u16, Value) + Send + Sync;
+
+#[derive(Clone)]
+/// A DynamoDB endpoint answered in process, recording the requests it receives
+pub(crate) struct MockDynamo {
+    respond: Arc<Respond>,
+    requests: Arc<Mutex<Vec<(String, Value)>>>,
+    delay: Duration,
+}
+
+impl MockDynamo {
+    pub fn new(respond: impl Fn(&str, &Value) -> (u16, Value) + Send + Sync + 'static) -> Self {
+        Self {
+            respond: Arc::new(respond),
+            requests: Arc::default(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Answer every request `delay` late, on the tokio timer
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// A client for `table` that sends its requests here, without retrying them
+    /// The SDK client has no sleep, so it neither waits between retries nor times out
+    pub fn client(&self, table: &str) -> TokenDynamoClient {
+        TokenDynamoClient::new(Client::from_conf(self.config().build()), table)
+    }
+
+    /// `client`, with `sleep` configured on the SDK client
+    pub fn client_with_sleep(
+        &self,
+        table: &str,
+        sleep: impl AsyncSleep + 'static,
+    ) -> TokenDynamoClient {
+        let config = self.config().sleep_impl(sleep).build();
+        TokenDynamoClient::new(Client::from_conf(config), table)
+    }
+
+    fn config(&self) -> config::Builder {
+        Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .http_client(self.clone())
+    }
+
+    /// The operation and body of every request received so far
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        lock(&self.requests).clone()
+    }
+}
+
+impl fmt::Debug for MockDynamo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockDynamo").finish_non_exhaustive()
+    }
+}
+
+impl HttpClient for MockDynamo {
+    fn http_connector(
+        &self,
+        _settings: &HttpConnectorSettings,
+        _components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(self.clone())
+    }
+}
+
+impl HttpConnector for MockDynamo {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        // The operation is named by the target header, e.g. `DynamoDB_20120810.Query`
+        let operation = request
+            .headers()
+            .get("x-amz-target")
+            .and_then(|target| target.split_once('.'))
+            .map(|(_, operation)| operation.to_string())
+            .unwrap_or_default();
+        let body = request
+            .body()
+            .bytes()
+            .and_then(|body| serde_json::from_slice(body).ok())
+            .unwrap_or_default();
+        let (status, response) = (self.respond)(&operation, &body);
+        lock(&self.requests).push((operation, body));
+
+        let status = StatusCode::try_from(status).expect("a valid status code");
+        let response = HttpResponse::new(status, SdkBody::from(response.to_string()));
+        if self.delay.is_zero() {
+            return HttpConnectorFuture::ready(Ok(response));
+        }
+        let delay = self.delay;
+        HttpConnectorFuture::new(async move {
+            tokio::time::sleep(delay).await;
+            Ok(response)
+        })
+    }
+}
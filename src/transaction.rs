@@ -0,0 +1,170 @@
+use crate::{
+    BatchLimitResult, LimitResult, RefillPolicy, TokenBucket, TokenBucketClient, TokenBucketError,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+/// How a `Transaction` handles some of its limits being denied
+pub enum TransactionMode {
+    /// Stop at the first deny or error and give back the tokens spent on the limits before it
+    #[default]
+    AllOrNothing,
+    /// Try every limit and keep the tokens spent on the ones that were allowed
+    /// Stops at the first error, by which point the limits before it have already been spent from
+    BestEffort,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The outcome of `Transaction::execute`
+pub struct TransactionResult {
+    /// Whether every limit was allowed
+    pub allowed: bool,
+    /// The result of each limit that was tried, in the order they were added
+    /// In `TransactionMode::AllOrNothing` this ends at the first deny, and the allowed results
+    /// before it have been refunded
+    pub limits: BatchLimitResult,
+}
+
+#[must_use = "a transaction does nothing until it's executed"]
+/// Several limits with their own costs decided together, built by `TokenBucket::transaction`
+///
+/// The limits are spent one after another, not atomically. A concurrent request can see the
+/// tokens of an all-or-nothing transaction before they are refunded.
+pub struct Transaction<'a, T: TokenBucketClient, R: RefillPolicy> {
+    bucket: &'a TokenBucket<T, R>,
+    mode: TransactionMode,
+    limits: Vec<(String, u64)>,
+}
+
+impl<'a, T: TokenBucketClient, R: RefillPolicy> Transaction<'a, T, R>
+where
+    T::Error: From<TokenBucketError>,
+{
+    pub(crate) fn new(bucket: &'a TokenBucket<T, R>) -> Self {
+        Self {
+            bucket,
+            mode: TransactionMode::default(),
+            limits: Vec::new(),
+        }
+    }
+
+    /// Limit `id` by `cost` as part of the transaction
+    pub fn add(mut self, id: impl Into<String>, cost: u64) -> Self {
+        self.limits.push((id.into(), cost));
+        self
+    }
+
+    /// `TransactionMode::AllOrNothing` by default
+    pub fn mode(mut self, mode: TransactionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Limit every added id in order
+    pub async fn execute(self) -> Result<TransactionResult, T::Error> {
+        let mut ids = Vec::with_capacity(self.limits.len());
+        let mut results = Vec::with_capacity(self.limits.len());
+        for (id, cost) in &self.limits {
+            let result = match self.bucket.limit(id, *cost).await {
+                Ok(result) => result,
+                Err(error) => {
+                    if self.mode == TransactionMode::AllOrNothing {
+                        self.roll_back(&results).await;
+                    }
+                    return Err(error);
+                }
+            };
+            ids.push(id.clone());
+            results.push(result);
+            if self.mode == TransactionMode::AllOrNothing
+                && matches!(result, LimitResult::Deny { .. })
+            {
+                self.roll_back(&results).await;
+                break;
+            }
+        }
+
+        let allowed = ids.len() == self.limits.len()
+            && results
+                .iter()
+                .all(|result| matches!(result, LimitResult::Allow { .. }));
+        Ok(TransactionResult {
+            allowed,
+            limits: BatchLimitResult { ids, results },
+        })
+    }
+
+    /// Refund the limits allowed so far, queueing the refund on the bucket if it can't be applied
+    async fn roll_back(&self, results: &[LimitResult]) {
+        for ((id, cost), result) in self.limits.iter().zip(results) {
+            if !matches!(result, LimitResult::Allow { .. }) {
+                continue;
+            }
+            if self.bucket.refund(id, *cost).await.is_err() {
+                self.bucket.queue_refund(id, *cost);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{bucket, settings};
+
+    #[tokio::test]
+    async fn all_or_nothing_refunds_on_deny() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+
+        let result = bucket
+            .transaction()
+            .add("a", 3)
+            .add("b", 11)
+            .add("c", 1)
+            .execute()
+            .await
+            .unwrap();
+        assert!(!result.allowed);
+        assert_eq!(result.limits.ids, ["a", "b"]);
+        assert_eq!(
+            result.limits.results[0],
+            LimitResult::Allow { remaining: 7 }
+        );
+        assert_eq!(bucket.peek("a").await.unwrap(), 10);
+        assert_eq!(bucket.peek("c").await.unwrap(), 10);
+
+        // An error rolls back too
+        let failed = bucket.transaction().add("a", 3).add("", 1).execute().await;
+        assert!(matches!(failed, Err(TokenBucketError::InvalidKey(_))));
+        assert_eq!(bucket.peek("a").await.unwrap(), 10);
+
+        let result = bucket
+            .transaction()
+            .add("a", 3)
+            .add("c", 1)
+            .execute()
+            .await
+            .unwrap();
+        assert!(result.allowed);
+        assert_eq!(bucket.peek("a").await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn best_effort_keeps_what_was_allowed() {
+        let (bucket, _) = bucket(settings(10, 10, 1, 60));
+
+        let result = bucket
+            .transaction()
+            .mode(TransactionMode::BestEffort)
+            .add("a", 3)
+            .add("b", 11)
+            .add("c", 1)
+            .execute()
+            .await
+            .unwrap();
+        assert!(!result.allowed);
+        assert_eq!(result.limits.ids, ["a", "b", "c"]);
+        assert_eq!(result.limits.denied().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(bucket.peek("a").await.unwrap(), 7);
+        assert_eq!(bucket.peek("c").await.unwrap(), 9);
+    }
+}
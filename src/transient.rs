@@ -0,0 +1,115 @@
+use crate::TokenBucketError;
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use aws_smithy_runtime_api::http::Response;
+use aws_smithy_types::body::SdkBody;
+
+/// The DynamoDB error codes that mean a request may succeed if retried later
+const TRANSIENT_ERROR_CODES: [&str; 5] = [
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "ThrottlingException",
+    "InternalServerError",
+    "ServiceUnavailable",
+];
+
+/// Tells errors worth retrying or failing over on apart from the ones that will happen again
+/// `FallbackClient` only switches to its fallback for transient errors of the primary
+pub trait TransientError {
+    fn is_transient(&self) -> bool;
+}
+
+impl TransientError for TokenBucketError {
+    /// Throttling, timeouts, connection failures and 5xx responses are transient.
+    /// Serialization, validation and bad request errors are permanent
+    fn is_transient(&self) -> bool {
+        match self {
+            TokenBucketError::DynamoGet(e) => is_transient_sdk_error(e),
+            TokenBucketError::DynamoGetItem(e) => is_transient_sdk_error(e),
+            TokenBucketError::DynamoBatchGet(e) => is_transient_sdk_error(e),
+            TokenBucketError::DynamoPut(e) => is_transient_sdk_error(e),
+            TokenBucketError::DynamoUpdate(e) => is_transient_sdk_error(e),
+            // DynamoDB leaves keys unprocessed when the table is throttled
+            TokenBucketError::UnprocessedKeys(_) => true,
+            TokenBucketError::Timeout(_) => true,
+            TokenBucketError::DynamoBuild(_)
+            | TokenBucketError::SerdeError(_)
+            | TokenBucketError::InvalidKey(_)
+            | TokenBucketError::InexactRate { .. }
+            | TokenBucketError::InvalidSettingsAttributes(_)
+            | TokenBucketError::Snapshot(_)
+            | TokenBucketError::SnapshotVersion(_)
+            | TokenBucketError::OutOfOrder { .. }
+            | TokenBucketError::DuplicateRow(_)
+            | TokenBucketError::InvalidSettings(_)
+            | TokenBucketError::SchemaVersion(_)
+            | TokenBucketError::NoSleepImpl => false,
+        }
+    }
+}
+
+fn is_transient_sdk_error<E: ProvideErrorMetadata>(error: &SdkError<E, Response<SdkBody>>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(e) => {
+            e.raw().status().is_server_error()
+                || e.err()
+                    .code()
+                    .is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::MockDynamo, RateLimitItem, TokenBucketClient};
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use std::{collections::HashMap, time::Duration};
+
+    /// The error `get_raw` fails with when DynamoDB answers `status` with error `code`
+    async fn dynamo_error(status: u16, code: &'static str) -> TokenBucketError {
+        let mock = MockDynamo::new(move |_, _| {
+            let body = serde_json::json!({
+                "__type": format!("com.amazonaws.dynamodb.v20120810#{code}"),
+                "message": "failed",
+            });
+            (status, body)
+        });
+        mock.client("table").get_raw("a").await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn throttling_and_server_errors_are_transient() {
+        assert!(dynamo_error(400, "ThrottlingException")
+            .await
+            .is_transient());
+        assert!(dynamo_error(400, "ProvisionedThroughputExceededException")
+            .await
+            .is_transient());
+        assert!(dynamo_error(500, "InternalServerError")
+            .await
+            .is_transient());
+        assert!(dynamo_error(503, "SomethingUnexpected")
+            .await
+            .is_transient());
+        assert!(TokenBucketError::Timeout(Duration::from_secs(1)).is_transient());
+        assert!(TokenBucketError::UnprocessedKeys(vec!["a".into()]).is_transient());
+    }
+
+    #[tokio::test]
+    async fn bad_requests_and_data_are_permanent() {
+        assert!(!dynamo_error(400, "ValidationException")
+            .await
+            .is_transient());
+        assert!(!dynamo_error(400, "ResourceNotFoundException")
+            .await
+            .is_transient());
+        let empty: HashMap<String, AttributeValue> = HashMap::new();
+        let serde = serde_dynamo::from_item::<_, RateLimitItem>(empty).unwrap_err();
+        assert!(!TokenBucketError::SerdeError(serde).is_transient());
+        assert!(!TokenBucketError::InvalidKey("".into()).is_transient());
+    }
+}